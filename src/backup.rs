@@ -0,0 +1,334 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::{helper, CustomError, MyError};
+
+// freedesktop trash layout: the file under `files/`, where it came from under `info/`
+const FILES_DIR: &str = "files";
+const INFO_DIR: &str = "info";
+const INFO_EXT: &str = "trashinfo";
+const OUTPUT_KEY: &str = "X-Rezip-Output";
+
+/// A source kept aside while its in-place book is written.
+#[derive(Clone, Debug)]
+pub struct Backup {
+    pub file: PathBuf,
+    pub info: PathBuf,
+}
+
+// copy, not move: the source stays in place until its book has replaced it
+pub fn backup_file(source: &Path, output: &Path, backup_dir: &str) -> Result<Backup, MyError> {
+    let root = helper::resolve_output_root(backup_dir)?;
+    fs::create_dir_all(root.join(FILES_DIR))?;
+    fs::create_dir_all(root.join(INFO_DIR))?;
+
+    let source = fs::canonicalize(source)?;
+    let file_name = source
+        .file_name()
+        .ok_or(CustomError::new(&format!(
+            "cannot get file_name from {:?}",
+            source
+        )))?
+        .to_string_lossy()
+        .into_owned();
+
+    // same named sources from different folders get numbered
+    let mut name = file_name.clone();
+    let mut i = 1;
+    while root.join(FILES_DIR).join(&name).exists()
+        || root
+            .join(INFO_DIR)
+            .join(format!("{name}.{INFO_EXT}"))
+            .exists()
+    {
+        name = format!("{file_name}.{i}");
+        i += 1;
+    }
+
+    let backup = Backup {
+        file: root.join(FILES_DIR).join(&name),
+        info: root.join(INFO_DIR).join(format!("{name}.{INFO_EXT}")),
+    };
+    fs::copy(&source, &backup.file)?;
+    fs::File::open(&backup.file)?.sync_all()?;
+    fs::write(
+        &backup.info,
+        format!(
+            "[Trash Info]\nPath={}\nDeletionDate={}\n{OUTPUT_KEY}={}\n",
+            percent_encode(&source),
+            deletion_date(),
+            percent_encode(output)
+        ),
+    )?;
+    Ok(backup)
+}
+
+// the books written in place of the source, one line each, `restore` removes them all
+pub fn set_outputs(backup: &Backup, outputs: &[String]) -> Result<(), MyError> {
+    let info = fs::read_to_string(&backup.info)?;
+    let mut lines: Vec<String> = info
+        .lines()
+        .filter(|line| !line.starts_with(&format!("{OUTPUT_KEY}=")))
+        .map(String::from)
+        .collect();
+    for output in outputs {
+        lines.push(format!(
+            "{OUTPUT_KEY}={}",
+            percent_encode(Path::new(output))
+        ));
+    }
+    fs::write(&backup.info, lines.join("\n") + "\n")?;
+    Ok(())
+}
+
+// the book was not written, the source is still in place
+pub fn discard(backup: &Backup) -> Result<(), MyError> {
+    fs::remove_file(&backup.file)?;
+    fs::remove_file(&backup.info)?;
+    Ok(())
+}
+
+/// One `.trashinfo` of the backup dir.
+struct TrashInfo {
+    info: PathBuf,
+    file: PathBuf,
+    original: PathBuf,
+    outputs: Vec<PathBuf>,
+    deletion_date: String,
+    /// `n` of a `name.n` backup, 0 for the first one of a name
+    suffix: u32,
+}
+
+fn read_info(root: &Path, info_path: PathBuf) -> Result<Option<TrashInfo>, MyError> {
+    let info = fs::read_to_string(&info_path)?;
+    let values = |key: &str| -> Vec<String> {
+        info.lines()
+            .filter_map(|line| Some(line.strip_prefix(key)?.strip_prefix('=')?.to_string()))
+            .collect()
+    };
+    let Some(original) = values("Path").pop() else {
+        eprintln!("[restore] no Path in {:?}", info_path);
+        return Ok(None);
+    };
+    let original = PathBuf::from(percent_decode(&original));
+    let name = info_path
+        .file_stem()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into_owned();
+    let base = original.file_name().unwrap_or_default().to_string_lossy();
+    let suffix = name
+        .strip_prefix(base.as_ref())
+        .and_then(|rest| rest.strip_prefix('.'))
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(0);
+    Ok(Some(TrashInfo {
+        file: root.join(FILES_DIR).join(&name),
+        original,
+        outputs: values(OUTPUT_KEY)
+            .iter()
+            .map(|output| PathBuf::from(percent_decode(output)))
+            .collect(),
+        deletion_date: values("DeletionDate").pop().unwrap_or_default(),
+        suffix,
+        info: info_path,
+    }))
+}
+
+// put every backup whose original path is under `filter` (all if empty) back, removing
+// the books that replaced it, returns (backup, restored) paths. A source rezipped in place
+// more than once has a backup per run, the oldest one is the original and the later ones
+// (outputs of earlier runs) are dropped
+pub fn restore(backup_dir: &str, filter: &str) -> Result<Vec<(PathBuf, PathBuf)>, MyError> {
+    let root = helper::resolve_output_root(backup_dir)?;
+    let filter = if filter.is_empty() {
+        None
+    } else {
+        Some(fs::canonicalize(filter).unwrap_or(PathBuf::from(filter)))
+    };
+
+    let mut restored = vec![];
+    let info_dir = root.join(INFO_DIR);
+    if !info_dir.is_dir() {
+        return Ok(restored);
+    }
+    let mut by_original: HashMap<PathBuf, Vec<TrashInfo>> = HashMap::new();
+    for entry in fs::read_dir(&info_dir)?.filter_map(|e| e.ok()) {
+        let info_path = entry.path();
+        if info_path.extension().unwrap_or_default() != INFO_EXT {
+            continue;
+        }
+        let Some(info) = read_info(&root, info_path)? else {
+            continue;
+        };
+        if let Some(filter) = &filter {
+            if !info.original.starts_with(filter) {
+                continue;
+            }
+        }
+        by_original
+            .entry(info.original.clone())
+            .or_default()
+            .push(info);
+    }
+
+    let mut originals: Vec<PathBuf> = by_original.keys().cloned().collect();
+    originals.sort();
+    for original in originals {
+        let mut infos = by_original.remove(&original).unwrap_or_default();
+        infos.sort_by(|a, b| (&a.deletion_date, a.suffix).cmp(&(&b.deletion_date, b.suffix)));
+        for output in infos.iter().flat_map(|info| &info.outputs) {
+            // a book with another extension (`vol1.7z` -> `vol1.cbz`) does not get overwritten
+            if *output != original && output.exists() {
+                fs::remove_file(output)?;
+            }
+        }
+        let oldest = infos.remove(0);
+        for later in infos {
+            fs::remove_file(&later.file)?;
+            fs::remove_file(&later.info)?;
+        }
+
+        if let Some(parent) = original.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // across file systems a rename is not possible
+        if fs::rename(&oldest.file, &original).is_err() {
+            fs::copy(&oldest.file, &original)?;
+            fs::remove_file(&oldest.file)?;
+        }
+        fs::remove_file(&oldest.info)?;
+        restored.push((oldest.file, original));
+    }
+    Ok(restored)
+}
+
+// the trash spec escapes `Path` like a URL path, the output keys go the same way
+fn percent_encode(path: &Path) -> String {
+    let mut encoded = String::new();
+    for byte in path.to_string_lossy().bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    encoded
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+// local time, as the trash spec has it
+fn deletion_date() -> String {
+    chrono::Local::now().format("%Y-%m-%dT%H:%M:%S").to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setup() -> (tempfile::TempDir, PathBuf, String) {
+        let dir = tempfile::tempdir().unwrap();
+        let root = fs::canonicalize(dir.path()).unwrap();
+        let backup_dir = root.join("backup").to_string_lossy().into_owned();
+        (dir, root, backup_dir)
+    }
+
+    fn leftovers(backup_dir: &str) -> usize {
+        [FILES_DIR, INFO_DIR]
+            .iter()
+            .map(|sub| {
+                fs::read_dir(Path::new(backup_dir).join(sub))
+                    .unwrap()
+                    .count()
+            })
+            .sum()
+    }
+
+    #[test]
+    fn restore_puts_source_back() {
+        let (_dir, root, backup_dir) = setup();
+        let source = root.join("vol 1 第一.zip");
+        let output = root.join("vol 1 第一.cbz");
+        fs::write(&source, "source").unwrap();
+        let backup = backup_file(&source, &output, &backup_dir).unwrap();
+        let info = fs::read_to_string(&backup.info).unwrap();
+        assert!(info.contains("/vol%201%20%E7%AC%AC%E4%B8%80.zip\n"));
+
+        fs::write(&output, "book").unwrap();
+        fs::remove_file(&source).unwrap();
+        let restored = restore(&backup_dir, "").unwrap();
+        assert_eq!(restored, vec![(backup.file, source.clone())]);
+        assert_eq!(fs::read_to_string(&source).unwrap(), "source");
+        assert!(!output.exists());
+        assert_eq!(leftovers(&backup_dir), 0);
+    }
+
+    #[test]
+    fn restore_removes_every_output() {
+        let (_dir, root, backup_dir) = setup();
+        let source = root.join("box.zip");
+        fs::write(&source, "source").unwrap();
+        let backup = backup_file(&source, &root.join("box.cbz"), &backup_dir).unwrap();
+        let outputs = ["box/vol1.cbz", "box/vol2.cbz"].map(|name| root.join(name));
+        fs::create_dir(root.join("box")).unwrap();
+        for output in &outputs {
+            fs::write(output, "inner").unwrap();
+        }
+        let outputs = outputs.map(|output| output.to_string_lossy().into_owned());
+        set_outputs(&backup, &outputs).unwrap();
+        fs::remove_file(&source).unwrap();
+
+        restore(&backup_dir, "").unwrap();
+        assert_eq!(fs::read_to_string(&source).unwrap(), "source");
+        assert!(outputs.iter().all(|output| !Path::new(output).exists()));
+    }
+
+    #[test]
+    fn restore_keeps_oldest_duplicate() {
+        let (_dir, root, backup_dir) = setup();
+        let source = root.join("vol1.cbz");
+        fs::write(&source, "first").unwrap();
+        let first = backup_file(&source, &source, &backup_dir).unwrap();
+        // the second run backs up the book of the first one
+        fs::write(&source, "second").unwrap();
+        let second = backup_file(&source, &source, &backup_dir).unwrap();
+        assert_eq!(second.file.file_name().unwrap(), "vol1.cbz.1");
+
+        let restored = restore(&backup_dir, "").unwrap();
+        assert_eq!(restored, vec![(first.file, source.clone())]);
+        assert_eq!(fs::read_to_string(&source).unwrap(), "first");
+        assert_eq!(leftovers(&backup_dir), 0);
+    }
+
+    #[test]
+    fn percent_round_trip() {
+        for path in ["/a b/c%d.zip", "/漫画/第1巻.cbz", "/plain/vol-1_x~.zip"] {
+            assert_eq!(percent_decode(&percent_encode(Path::new(path))), path);
+        }
+        assert_eq!(percent_encode(Path::new("/a b")), "/a%20b");
+    }
+}
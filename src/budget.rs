@@ -0,0 +1,61 @@
+use std::sync::{Condvar, Mutex, MutexGuard};
+
+/// Bytes the books of a batch may hold at once, each book reserves its `helper::book_cost`
+/// before it starts.
+#[derive(Debug, Default)]
+pub struct MemoryBudget {
+    /// unlimited if `None`
+    limit: Option<u64>,
+    used: Mutex<u64>,
+    freed: Condvar,
+}
+
+/// Held while a book runs, gives its bytes back on drop.
+#[derive(Debug)]
+pub struct Reservation<'a> {
+    budget: &'a MemoryBudget,
+    bytes: u64,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: Option<u64>) -> MemoryBudget {
+        MemoryBudget {
+            limit,
+            ..MemoryBudget::default()
+        }
+    }
+
+    // blocks until the bytes fit, a book bigger than the whole budget waits to run alone
+    pub fn reserve(&self, bytes: u64) -> Reservation<'_> {
+        let Some(limit) = self.limit else {
+            return Reservation {
+                budget: self,
+                bytes: 0,
+            };
+        };
+        let bytes = bytes.min(limit);
+        let mut used = self.lock();
+        while *used + bytes > limit {
+            used = self.freed.wait(used).unwrap_or_else(|e| e.into_inner());
+        }
+        *used += bytes;
+        Reservation {
+            budget: self,
+            bytes,
+        }
+    }
+
+    // a panicking book must not stall the rest of the batch
+    fn lock(&self) -> MutexGuard<'_, u64> {
+        self.used.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        if self.bytes > 0 {
+            *self.budget.lock() -= self.bytes;
+            self.budget.freed.notify_all();
+        }
+    }
+}
@@ -0,0 +1,581 @@
+use std::path::Path;
+
+use crate::constant::{
+    ARCHIVE_EXT, BACKUP_DIR, LEGACY_ENCODINGS, METHOD_DEFAULT, PASSWORD_ENV, STDIO_PATH,
+};
+use crate::helper::{self, NameEncoding};
+use crate::{CustomError, MyError};
+
+/// Container written for every book.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    Zip,
+    Tar,
+}
+
+/// What to do with archives found inside an archive.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NestedMode {
+    /// expand them into sub folders of the outer book
+    Merge,
+    /// rezip every inner archive as a book of its own
+    Split,
+}
+
+/// How much of the folder structure of a book is kept.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlattenMode {
+    /// keep the tree as extracted
+    Off,
+    /// pull the contents of a lone top-level folder up to the root
+    Top,
+    /// also turn chapter folders into name prefixes, `c01/001.jpg` -> `c01_001.jpg`
+    Chapters,
+}
+
+/// Which zip writer builds the books.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ZipWriterKind {
+    /// async_zip, streams every file with tokio I/O
+    Async,
+    /// the `zip` crate, blocking I/O, supports `--encrypt` and the pipeline
+    Sync,
+}
+
+/// What to do when the output of a book already exists, decided before any work.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Collision {
+    Skip,
+    Overwrite,
+    /// write `vol1_1.cbz`, `vol1_2.cbz`... next to it
+    Rename,
+    /// skip if the existing output was made from the same source with the same settings,
+    /// overwrite otherwise
+    Compare,
+}
+
+/// Where the mtime of every entry comes from in deterministic mode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MtimeSource {
+    /// 1980-01-01 00:00:00, the zip epoch
+    Fixed,
+    /// last modified time of the input archive (or folder)
+    Source,
+}
+
+/// What a run does.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Command {
+    Rezip,
+    /// put backed up sources back where they were, see `backup::restore`
+    Restore,
+    /// report how the entry names of zips are decoded, nothing is written
+    Inspect,
+}
+
+/// Run options parsed from the command line.
+///
+/// Usage: `comic-rezip <input_dir> <output_dir> [--ext zip,cbz] [--out-ext cbz] [--no-sniff]
+/// [--format zip|tar] [--pack] [--nested-depth 3] [--nested merge|split]
+/// [--password-file FILE] [--compress deflate|zstd|bzip2|stored] [--level N]
+/// [--deterministic] [--mtime fixed|source] [--renumber] [--flatten off|top|chapters]
+/// [--on-exist skip|overwrite|rename|compare] [--zip-writer async|sync]
+/// [--encrypt (password in $REZIP_PASSWORD) | --encrypt-file FILE] [--memory-budget 512M]
+/// [--pipeline] [--encoding GBK] [--encoding-for PATH_OR_GLOB=GBK]
+/// [--encodings GBK,Big5,Shift_JIS,EUC-KR]`,
+/// `comic-rezip <input_dir> --in-place [--backup-dir DIR] [options]` or
+/// `comic-rezip restore [<path>] [--backup-dir DIR]` or
+/// `comic-rezip inspect <path> [--json] [--encoding GBK] [--encoding-for ...] [--encodings ...]`
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub command: Command,
+    /// scan root, or for `restore` the path whose backups are restored (all if empty), or
+    /// for `inspect` the zip or folder of zips
+    pub input: String,
+    /// output root, absolute, `./` or `../` cwd-relative, home-relative otherwise; books
+    /// mirror their path under `input`
+    pub output: String,
+    /// lowercase extensions (without dot) treated as archive input
+    pub archive_ext: Vec<String>,
+    /// force the extension of output archives, keep the input one if `None`
+    pub out_ext: Option<String>,
+    /// also accept files whose first bytes are a known archive signature
+    pub sniff: bool,
+    pub format: OutputFormat,
+    /// pack leaf image folders into books instead of rezipping archives
+    pub pack: bool,
+    /// how many levels of archives inside archives get expanded, 0 keeps them opaque
+    pub nested_depth: usize,
+    pub nested: NestedMode,
+    /// password list tried in order on encrypted zips, after the per-directory `.passwords`
+    pub password_file: Option<String>,
+    /// zip method for entries that are not already compressed (text, xml, png...)
+    pub compress: zip::CompressionMethod,
+    /// method specific level, `None` for the method's default
+    pub level: Option<i32>,
+    /// same input, same output bytes
+    pub deterministic: bool,
+    pub mtime: MtimeSource,
+    /// natural-sort the pages of every folder and rename them to `001.jpg, 002.jpg...`
+    pub renumber: bool,
+    pub flatten: FlattenMode,
+    pub on_exist: Collision,
+    /// replace every source archive by its book, `output` is `input` then
+    pub in_place: bool,
+    /// where in-place runs keep the replaced sources
+    pub backup_dir: String,
+    /// always sync for `-` as output, see `Config::from_args`
+    pub zip_writer: ZipWriterKind,
+    /// ZipCrypto password every book is written with, forces the sync writer. From the
+    /// environment or a file, never from argv where every user can read it
+    pub encrypt: Option<String>,
+    /// bytes all running books may hold together, unlimited if `None`
+    pub memory_budget: Option<u64>,
+    /// stream zip books entry by entry into the output instead of extracting them first,
+    /// unchanged entries are copied without recompressing. Always the sync writer, books
+    /// the pipeline cannot handle are extracted as usual
+    pub pipeline: bool,
+    /// decode every name without an encoding of its own with this one
+    pub encoding: Option<String>,
+    /// (directory or glob, encoding), the first one matching an archive beats `encoding`
+    pub encoding_rules: Vec<(String, String)>,
+    /// legacy encodings tried for names without an encoding of their own, in order
+    pub encodings: Vec<String>,
+    /// `inspect` prints json instead of the readable report
+    pub json: bool,
+}
+
+impl Config {
+    pub fn from_args(args: &[String]) -> Result<Config, MyError> {
+        let mut positional = vec![];
+        let mut archive_ext: Vec<String> = ARCHIVE_EXT.iter().map(|ext| ext.to_string()).collect();
+        let mut out_ext = None;
+        let mut sniff = true;
+        let mut format = OutputFormat::Zip;
+        let mut pack = false;
+        let mut nested_depth = 3;
+        let mut nested = NestedMode::Merge;
+        let mut password_file = None;
+        let mut compress = METHOD_DEFAULT;
+        let mut level = None;
+        let mut deterministic = false;
+        let mut mtime = MtimeSource::Fixed;
+        let mut renumber = false;
+        let mut flatten = FlattenMode::Off;
+        let mut on_exist = Collision::Skip;
+        let mut in_place = false;
+        let mut backup_dir = String::from(BACKUP_DIR);
+        let mut zip_writer = ZipWriterKind::Async;
+        let mut memory_budget = None;
+        let mut pipeline = false;
+        let mut encoding = None;
+        let mut encoding_rules = vec![];
+        let mut encodings: Vec<String> = LEGACY_ENCODINGS.iter().map(|e| e.to_string()).collect();
+        let mut json = false;
+        let mut encrypt = None;
+
+        let mut it = args.iter().skip(1);
+        while let Some(arg) = it.next() {
+            match arg.as_str() {
+                "--ext" => {
+                    archive_ext = parse_ext_list(&next_value(&mut it, arg)?);
+                }
+                "--out-ext" => {
+                    out_ext = Some(
+                        next_value(&mut it, arg)?
+                            .trim_start_matches('.')
+                            .to_string(),
+                    );
+                }
+                "--no-sniff" => sniff = false,
+                "--pack" => pack = true,
+                "--password-file" => password_file = Some(next_value(&mut it, arg)?),
+                "--compress" => {
+                    compress = match next_value(&mut it, arg)?.to_lowercase().as_str() {
+                        "deflate" => zip::CompressionMethod::Deflated,
+                        "zstd" => zip::CompressionMethod::Zstd,
+                        "bzip2" => zip::CompressionMethod::Bzip2,
+                        "stored" => zip::CompressionMethod::Stored,
+                        other => {
+                            return Err(MyError::from(CustomError::new(&format!(
+                                "unknown compression method {other}"
+                            ))));
+                        }
+                    };
+                }
+                "--level" => level = Some(next_value(&mut it, arg)?.parse()?),
+                "--deterministic" => deterministic = true,
+                "--mtime" => {
+                    mtime = match next_value(&mut it, arg)?.to_lowercase().as_str() {
+                        "fixed" => MtimeSource::Fixed,
+                        "source" => MtimeSource::Source,
+                        other => {
+                            return Err(MyError::from(CustomError::new(&format!(
+                                "unknown mtime source {other}"
+                            ))));
+                        }
+                    };
+                }
+                "--renumber" => renumber = true,
+                "--flatten" => {
+                    flatten = match next_value(&mut it, arg)?.to_lowercase().as_str() {
+                        "off" => FlattenMode::Off,
+                        "top" => FlattenMode::Top,
+                        "chapters" => FlattenMode::Chapters,
+                        other => {
+                            return Err(MyError::from(CustomError::new(&format!(
+                                "unknown flatten mode {other}"
+                            ))));
+                        }
+                    };
+                }
+                "--on-exist" => {
+                    on_exist = match next_value(&mut it, arg)?.to_lowercase().as_str() {
+                        "skip" => Collision::Skip,
+                        "overwrite" => Collision::Overwrite,
+                        "rename" => Collision::Rename,
+                        "compare" => Collision::Compare,
+                        other => {
+                            return Err(MyError::from(CustomError::new(&format!(
+                                "unknown collision policy {other}"
+                            ))));
+                        }
+                    };
+                }
+                "--in-place" => in_place = true,
+                "--backup-dir" => backup_dir = next_value(&mut it, arg)?,
+                "--zip-writer" => {
+                    zip_writer = match next_value(&mut it, arg)?.to_lowercase().as_str() {
+                        "async" => ZipWriterKind::Async,
+                        "sync" => ZipWriterKind::Sync,
+                        other => {
+                            return Err(MyError::from(CustomError::new(&format!(
+                                "unknown zip writer {other}"
+                            ))));
+                        }
+                    };
+                }
+                "--memory-budget" => memory_budget = Some(parse_size(&next_value(&mut it, arg)?)?),
+                "--pipeline" => pipeline = true,
+                "--encrypt" => encrypt = Some(env_password()?),
+                "--encrypt-file" => encrypt = Some(file_password(&next_value(&mut it, arg)?)?),
+                "--json" => json = true,
+                "--encoding" => encoding = Some(parse_encoding(&next_value(&mut it, arg)?)?),
+                "--encoding-for" => {
+                    let value = next_value(&mut it, arg)?;
+                    let Some((pattern, label)) = value.rsplit_once('=') else {
+                        return Err(MyError::from(CustomError::new(&format!(
+                            "option {arg} needs PATH_OR_GLOB=ENCODING, got {value}"
+                        ))));
+                    };
+                    encoding_rules.push((pattern.to_string(), parse_encoding(label)?));
+                }
+                "--encodings" => {
+                    encodings = next_value(&mut it, arg)?
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|label| !label.is_empty())
+                        .map(parse_encoding)
+                        .collect::<Result<_, _>>()?;
+                }
+                "--nested-depth" => nested_depth = next_value(&mut it, arg)?.parse()?,
+                "--nested" => {
+                    nested = match next_value(&mut it, arg)?.to_lowercase().as_str() {
+                        "merge" => NestedMode::Merge,
+                        "split" => NestedMode::Split,
+                        other => {
+                            return Err(MyError::from(CustomError::new(&format!(
+                                "unknown nested mode {other}"
+                            ))));
+                        }
+                    };
+                }
+                "--format" => {
+                    format = match next_value(&mut it, arg)?.to_lowercase().as_str() {
+                        "zip" => OutputFormat::Zip,
+                        "tar" => OutputFormat::Tar,
+                        other => {
+                            return Err(MyError::from(CustomError::new(&format!(
+                                "unknown output format {other}"
+                            ))));
+                        }
+                    };
+                }
+                _ if arg.starts_with("--") => {
+                    return Err(MyError::from(CustomError::new(&format!(
+                        "unknown option {arg}"
+                    ))));
+                }
+                _ => positional.push(arg.clone()),
+            }
+        }
+
+        if let Some(level) = level {
+            check_level(compress, level)?;
+        }
+
+        // async_zip cannot encrypt, and tar has nothing to encrypt with
+        if encrypt.is_some() {
+            if format == OutputFormat::Tar {
+                return Err(MyError::from(CustomError::new(
+                    "--encrypt and --encrypt-file need --format zip",
+                )));
+            }
+            zip_writer = ZipWriterKind::Sync;
+        }
+
+        let command = match positional.first().map(String::as_str) {
+            Some("restore") => Command::Restore,
+            Some("inspect") => Command::Inspect,
+            _ => Command::Rezip,
+        };
+        if command != Command::Rezip {
+            positional.remove(0);
+        }
+        let (input, output) = match (command, in_place, positional.as_slice()) {
+            (Command::Restore, _, []) => (String::new(), String::new()),
+            (Command::Restore, _, [path]) => (path.clone(), String::new()),
+            (Command::Inspect, _, [path]) => (path.clone(), String::new()),
+            // next to the source, wherever the cwd is
+            (Command::Rezip, true, [input]) => (
+                input.clone(),
+                std::env::current_dir()?
+                    .join(input)
+                    .to_string_lossy()
+                    .into_owned(),
+            ),
+            (Command::Rezip, false, [input, output]) => (input.clone(), output.clone()),
+            _ => {
+                return Err(MyError::from(CustomError::new(
+                    "usage: comic-rezip <input_dir> <output_dir> [options], \
+                     comic-rezip <input_dir> --in-place [options] \
+                     comic-rezip restore [<path>] [--backup-dir DIR] \
+                     or comic-rezip inspect <path> [--json]",
+                )));
+            }
+        };
+        // whatever reads a zip from stdout next likely streams it, and needs the sizes in the
+        // local headers. The sync writer seeks back to put them there, async_zip puts them
+        // in a data descriptor after the data
+        if output == STDIO_PATH {
+            zip_writer = ZipWriterKind::Sync;
+        }
+
+        Ok(Config {
+            command,
+            input,
+            output,
+            archive_ext,
+            out_ext,
+            sniff,
+            format,
+            pack,
+            nested_depth,
+            nested,
+            password_file,
+            compress,
+            level,
+            deterministic,
+            mtime,
+            renumber,
+            flatten,
+            on_exist,
+            in_place,
+            backup_dir,
+            zip_writer,
+            encrypt,
+            memory_budget,
+            pipeline,
+            encoding,
+            encoding_rules,
+            encodings,
+            json,
+        })
+    }
+
+    /// every option that changes the bytes of a book, part of its fingerprint
+    pub fn output_settings(&self) -> String {
+        format!(
+            "{:?} {:?} {:?} {:?} {} {:?} {} {:?} {} {:?} {:?} {} {} {:?} {:?} {:?}",
+            self.out_ext,
+            self.format,
+            self.compress,
+            self.level,
+            self.deterministic,
+            self.mtime,
+            self.renumber,
+            self.flatten,
+            self.nested_depth,
+            self.nested,
+            self.zip_writer,
+            // whether, not the password itself, the fingerprint is readable
+            self.encrypt.is_some(),
+            self.pipeline,
+            self.encoding,
+            self.encoding_rules,
+            self.encodings,
+        )
+    }
+
+    /// the name encoding options for one archive, `None` for stdin
+    pub fn name_encoding(&self, archive: Option<&Path>) -> NameEncoding {
+        let rule = archive.and_then(|archive| {
+            let relative = archive.strip_prefix(&self.input).unwrap_or(archive);
+            self.encoding_rules.iter().find(|(pattern, _)| {
+                if pattern.contains(&['*', '?'][..]) {
+                    helper::glob_match(pattern, &archive.to_string_lossy())
+                        || helper::glob_match(pattern, &relative.to_string_lossy())
+                } else {
+                    archive.starts_with(pattern) || relative.starts_with(pattern)
+                }
+            })
+        });
+        NameEncoding {
+            forced: rule
+                .map(|(_, label)| label.clone())
+                .or(self.encoding.clone()),
+            archive: None,
+            candidates: self.encodings.clone(),
+        }
+    }
+}
+
+fn next_value<'a>(
+    it: &mut impl Iterator<Item = &'a String>,
+    option: &str,
+) -> Result<String, MyError> {
+    it.next()
+        .cloned()
+        .ok_or_else(|| MyError::from(CustomError::new(&format!("option {option} needs a value"))))
+}
+
+fn env_password() -> Result<String, MyError> {
+    std::env::var(PASSWORD_ENV)
+        .ok()
+        .filter(|password| !password.is_empty())
+        .ok_or_else(|| {
+            MyError::from(CustomError::new(&format!(
+                "--encrypt takes the password from {PASSWORD_ENV}, which is not set"
+            )))
+        })
+}
+
+// the first line, without its line ending like the password lists
+fn file_password(path: &str) -> Result<String, MyError> {
+    std::fs::read_to_string(path)?
+        .lines()
+        .next()
+        .map(|line| line.trim_end_matches('\r').to_string())
+        .filter(|password| !password.is_empty())
+        .ok_or_else(|| MyError::from(CustomError::new(&format!("no password in {path}"))))
+}
+
+fn parse_ext_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|ext| ext.trim().trim_start_matches('.').to_lowercase())
+        .filter(|ext| !ext.is_empty())
+        .collect()
+}
+
+// the zip writer refuses a level outside what the method takes, and any level for stored,
+// only once the first book is written
+fn check_level(method: zip::CompressionMethod, level: i32) -> Result<(), MyError> {
+    let range = match method {
+        zip::CompressionMethod::Deflated => 0..=9,
+        // bzip2 has no level 0, its block size would be 0 and the encoder panics
+        zip::CompressionMethod::Bzip2 => 1..=9,
+        zip::CompressionMethod::Zstd => 1..=22,
+        _ => {
+            return Err(MyError::from(CustomError::new(&format!(
+                "--level does not apply to the {method} method"
+            ))));
+        }
+    };
+    if !range.contains(&level) {
+        return Err(MyError::from(CustomError::new(&format!(
+            "--level {level} is out of range {}..={} for the {method} method",
+            range.start(),
+            range.end()
+        ))));
+    }
+    Ok(())
+}
+
+// any whatwg label the decoder knows, kept as given
+fn parse_encoding(label: &str) -> Result<String, MyError> {
+    match encoding::label::encoding_from_whatwg_label(label) {
+        Some(_) => Ok(label.to_string()),
+        None => Err(MyError::from(CustomError::new(&format!(
+            "unknown encoding {label}"
+        )))),
+    }
+}
+
+// `1048576`, `512K`, `512M`, `2G`
+fn parse_size(value: &str) -> Result<u64, MyError> {
+    let value = value.trim().to_uppercase();
+    let (number, unit) = match value.char_indices().find(|(_, c)| !c.is_ascii_digit()) {
+        Some((i, _)) => value.split_at(i),
+        None => (value.as_str(), ""),
+    };
+    let unit = match unit.trim_end_matches(&['B', 'I'][..]) {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        other => {
+            return Err(MyError::from(CustomError::new(&format!(
+                "unknown size unit {other}"
+            ))));
+        }
+    };
+    number
+        .parse::<u64>()?
+        .checked_mul(unit)
+        .ok_or_else(|| MyError::from(CustomError::new(&format!("size {value} is too large"))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_size_units() {
+        assert_eq!(parse_size("1048576").unwrap(), 1 << 20);
+        assert_eq!(parse_size("512K").unwrap(), 512 << 10);
+        assert_eq!(parse_size("512m").unwrap(), 512 << 20);
+        assert_eq!(parse_size(" 2GiB ").unwrap(), 2 << 30);
+        assert_eq!(parse_size("3MB").unwrap(), 3 << 20);
+    }
+
+    #[test]
+    fn parse_size_rejects_bad_values() {
+        assert!(parse_size("").is_err());
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("12T").is_err());
+        assert!(parse_size("-1M").is_err());
+        // fits in u64 only before the multiplier
+        assert!(parse_size("18446744073709551615K").is_err());
+        assert!(parse_size("99999999999999999999").is_err());
+    }
+
+    #[test]
+    fn check_level_ranges() {
+        assert!(check_level(zip::CompressionMethod::Deflated, 0).is_ok());
+        assert!(check_level(zip::CompressionMethod::Bzip2, 0).is_err());
+        assert!(check_level(zip::CompressionMethod::Bzip2, 9).is_ok());
+        assert!(check_level(zip::CompressionMethod::Zstd, 22).is_ok());
+        assert!(check_level(zip::CompressionMethod::Stored, 1).is_err());
+    }
+
+    #[test]
+    fn encrypt_password_from_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("password");
+        std::fs::write(&file, "pass word\r\nignored\n").unwrap();
+        assert_eq!(file_password(file.to_str().unwrap()).unwrap(), "pass word");
+        std::fs::write(&file, "\n").unwrap();
+        assert!(file_password(file.to_str().unwrap()).is_err());
+    }
+}
@@ -1,7 +1,116 @@
+/// `-` as input reads an archive from stdin, as output writes the result to stdout
+pub const STDIO_PATH: &str = "-";
+pub const ARCHIVE_EXT: [&str; 8] = ["zip", "cbz", "7z", "cb7", "tar", "cbt", "tar.gz", "tgz"];
+pub const SEVENZ_EXT: [&str; 2] = ["7z", "cb7"];
+pub const TAR_EXT: [&str; 2] = ["tar", "cbt"];
+pub const TAR_GZ_EXT: [&str; 2] = ["tar.gz", "tgz"];
+/// zip-based formats that are not books, the sniffer leaves them alone. Rezipping would
+/// break their stored `mimetype` entry and entry order
+pub const ZIP_CONTAINER_EXT: [&str; 12] = [
+    "epub", "docx", "xlsx", "pptx", "odt", "ods", "odp", "apk", "jar", "war", "xpi", "ipa",
+];
+/// local file header / empty archive signatures
+pub const ZIP_MAGIC: [[u8; 4]; 2] = [*b"PK\x03\x04", *b"PK\x05\x06"];
+pub const SEVENZ_MAGIC: [u8; 6] = *b"7z\xBC\xAF\x27\x1C";
+/// ustar magic lives at offset 257 of the first header block
+pub const TAR_MAGIC: [u8; 5] = *b"ustar";
+pub const TAR_MAGIC_OFFSET: usize = 257;
+/// leading bytes an archive is sniffed by, up to the end of the tar magic
+pub const ARCHIVE_SNIFF_LEN: usize = TAR_MAGIC_OFFSET + TAR_MAGIC.len();
+/// per-directory password list, one password per line, tried before --password-file
+pub const PASSWORD_LIST_FILE: &str = ".passwords";
+/// environment variable `--encrypt` takes the password from
+pub const PASSWORD_ENV: &str = "REZIP_PASSWORD";
 pub const TRASH_EXT: [&str; 6] = ["url", "db", "txt", "html", "torrent", "part"];
+/// resource fork folder added by macOS, never part of a book
+pub const TRASH_DIR: &str = "__MACOSX";
 pub const TRANSFORM_EXT: [&str; 5] = ["png", "bmp", "JPG", "webm", "webp"];
 pub const SHIFT_JIS: &str = "Shift_JIS";
 pub const ASCII: &str = "ascii";
 pub const UTF8: &str = "utf-8";
 pub const FALLBACK_ENCODING: &str = SHIFT_JIS;
+/// Multi-byte encodings an unflagged name may be in instead of CP437, tried strictly.
+pub const LEGACY_ENCODINGS: [&str; 4] = [SHIFT_JIS, "GBK", "Big5", "EUC-KR"];
+/// Lead byte ranges of the scripts names are expected in, per lowercase encoding label:
+/// full-width symbols and kana plus level 1 kanji, GB2312 level 1 hanzi, frequent Big5
+/// hanzi, KS X 1001 hangul. Characters from the rest of an encoding are rare in names.
+pub const EXPECTED_LEAD_BYTES: [(&str, &[(u8, u8)]); 6] = [
+    ("shift_jis", &[(0x81, 0x83), (0x88, 0x98)]),
+    ("gbk", &[(0xA1, 0xA3), (0xB0, 0xD7)]),
+    ("gb18030", &[(0xA1, 0xA3), (0xB0, 0xD7)]),
+    ("gb2312", &[(0xA1, 0xA3), (0xB0, 0xD7)]),
+    ("big5", &[(0xA1, 0xA3), (0xA4, 0xC6)]),
+    ("euc-kr", &[(0xA1, 0xA3), (0xB0, 0xC8)]),
+];
+/// CP437 bytes 0x80..=0xFF, the lower half is ASCII.
+pub const CP437_HIGH: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å', //
+    'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ', //
+    'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»', //
+    '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕', '╣', '║', '╗', '╝', '╜', '╛', '┐', //
+    '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦', '╠', '═', '╬', '╧', //
+    '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐', '▀', //
+    'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩', //
+    '≡', '±', '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{a0}',
+];
 pub const METHOD_STORED: zip::CompressionMethod = zip::CompressionMethod::Stored;
+pub const METHOD_DEFAULT: zip::CompressionMethod = zip::CompressionMethod::Deflated;
+/// how many leading bytes are read to sniff already compressed content
+pub const SNIFF_LEN: usize = 16;
+/// (offset, signature) of formats that do not shrink any further, written as Stored
+pub const COMPRESSED_MAGIC: [(usize, &[u8]); 14] = [
+    (0, b"\xFF\xD8\xFF"),         // jpeg
+    (0, b"GIF8"),                 // gif
+    (8, b"WEBP"),                 // webp (RIFF container)
+    (4, b"ftyp"),                 // mp4, mov, avif, heic
+    (0, b"\x1A\x45\xDF\xA3"),     // webm, mkv
+    (0, b"\xFF\x0A"),             // jpeg xl codestream
+    (0, b"\x00\x00\x00\x0CJXL "), // jpeg xl container
+    (0, b"PK\x03\x04"),           // zip
+    (0, b"7z\xBC\xAF\x27\x1C"),   // 7z
+    (0, b"Rar!"),                 // rar
+    (0, b"\x1F\x8B"),             // gzip
+    (0, b"BZh"),                  // bzip2
+    (0, b"\x28\xB5\x2F\xFD"),     // zstd
+    (0, b"ID3"),                  // mp3
+];
+
+/// Info-ZIP extended timestamp extra field id ("UT")
+pub const EXTENDED_TIMESTAMP_ID: u16 = 0x5455;
+
+/// Info-ZIP Unicode Path extra field id ("up"), a UTF-8 copy of a legacy encoded name
+pub const UNICODE_PATH_ID: u16 = 0x7075;
+
+/// zip64 extended information extra field id, 64-bit sizes of a large entry
+pub const ZIP64_EXTRA_ID: u16 = 0x0001;
+
+/// signature of a zip local file header, "PK\x03\x04"
+pub const LOCAL_HEADER_SIG: u32 = 0x04034b50;
+/// optional signature of a zip data descriptor, "PK\x07\x08"
+pub const DATA_DESCRIPTOR_SIG: u32 = 0x08074b50;
+
+/// digits of renumbered page names at least, `001.jpg`
+pub const PAGE_NUMBER_WIDTH: usize = 3;
+
+/// digits of chapter prefixes at least, `c01_`
+pub const CHAPTER_NUMBER_WIDTH: usize = 2;
+
+/// archive comment (zip) or pax global `comment` (tar) marking the source of a book
+pub const FINGERPRINT_PREFIX: &str = "comic-rezip source sha256:";
+
+/// suffix of an output being written, `.vol1.cbz.rezip-partial`
+pub const PARTIAL_SUFFIX: &str = ".rezip-partial";
+/// a partial untouched for this long has no writer left, a running one keeps writing to it
+pub const PARTIAL_MAX_AGE: std::time::Duration = std::time::Duration::from_secs(15 * 60);
+
+/// default backup folder of in-place runs, trash-style `files/` and `info/` inside
+pub const BACKUP_DIR: &str = "~/.comic-rezip/backup";
+
+/// buffer of every streamed copy, whatever the file size
+pub const COPY_BUF_LEN: usize = 64 * 1024;
+/// how much of a page is read to find its dimensions, exif data can push the jpeg frame
+/// header that far
+pub const PROBE_LEN: usize = 64 * 1024;
+/// decoded bytes per encoded byte of a page whose dimensions are unknown, rgba of a
+/// typical png or webp page
+pub const DECODE_MULTIPLIER: u64 = 10;
@@ -1,10 +1,22 @@
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
 use std::ffi::OsStr;
-use std::path::Path;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use crate::constant::{ASCII, FALLBACK_ENCODING, UTF8};
+use crate::config::OutputFormat;
+use crate::constant::{
+    ARCHIVE_SNIFF_LEN, CHAPTER_NUMBER_WIDTH, COMPRESSED_MAGIC, COPY_BUF_LEN, CP437_HIGH,
+    DECODE_MULTIPLIER, EXPECTED_LEAD_BYTES, EXTENDED_TIMESTAMP_ID, FALLBACK_ENCODING,
+    FINGERPRINT_PREFIX, LEGACY_ENCODINGS, PAGE_NUMBER_WIDTH, PARTIAL_SUFFIX, PASSWORD_LIST_FILE,
+    PROBE_LEN, SEVENZ_EXT, SEVENZ_MAGIC, TAR_EXT, TAR_GZ_EXT, TAR_MAGIC, TAR_MAGIC_OFFSET,
+    TRANSFORM_EXT, TRASH_DIR, TRASH_EXT, UNICODE_PATH_ID, ZIP_CONTAINER_EXT, ZIP_MAGIC,
+};
 use crate::{my_error::CustomError, MyError};
-use chalk_rs::Chalk;
-use encoding::{label::encoding_from_whatwg_label, DecoderTrap};
+use encoding::{label::encoding_from_whatwg_label, DecoderTrap, EncoderTrap};
+use sha2::{Digest, Sha256};
+use walkdir::WalkDir;
 
 pub fn validate_file_name(file_name: &str) -> Result<(), MyError> {
     if file_name.contains('\\')
@@ -23,33 +35,460 @@ pub fn validate_file_name(file_name: &str) -> Result<(), MyError> {
     }
 }
 
-pub fn get_out_zip_path(origin_filename: &str, output_path: &str) -> Result<String, MyError> {
+// absolute as is, `./out` and `../out` under the cwd, `~/out` and any other relative
+// path under the home directory, where a bare `out` has always been written
+pub fn resolve_output_root(output_path: &str) -> Result<PathBuf, MyError> {
+    let path = Path::new(output_path);
+    if path.is_absolute() {
+        return Ok(path.to_path_buf());
+    }
+    if matches!(
+        path.components().next(),
+        Some(Component::CurDir | Component::ParentDir)
+    ) {
+        return Ok(std::env::current_dir()?.join(path));
+    }
     Ok(dirs_next::home_dir()
         .ok_or(CustomError::new("cannot get home_dir"))?
-        .join(Path::new(&output_path))
-        .join(
-            Path::new(&origin_filename)
-                .file_name()
-                .ok_or(CustomError::new(&format!(
-                    "cannot get file_name from {origin_filename}"
-                )))?,
-        )
+        .join(path.strip_prefix("~").unwrap_or(path)))
+}
+
+// `<input>/A/vol1.zip` is written into `<output>/A/`, so same named books never collide
+pub fn mirror_out_dir(input_root: &str, origin: &str, output_path: &str) -> String {
+    let relative_dir = Path::new(origin)
+        .strip_prefix(input_root)
+        .ok()
+        .and_then(|relative| relative.parent())
+        .unwrap_or(Path::new(""));
+    Path::new(output_path)
+        .join(relative_dir)
+        .to_string_lossy()
+        .into_owned()
+}
+
+pub fn get_out_zip_path(
+    origin_filename: &str,
+    output_path: &str,
+    out_ext: Option<&str>,
+    format: OutputFormat,
+) -> Result<String, MyError> {
+    let mut out_path =
+        resolve_output_root(output_path)?.join(Path::new(&origin_filename).file_name().ok_or(
+            CustomError::new(&format!("cannot get file_name from {origin_filename}")),
+        )?);
+    let origin_ext = archive_ext(&out_path);
+    let ext = match out_ext {
+        Some(ext) => Some(ext.to_string()),
+        None => rezip_ext(&origin_ext, format),
+    };
+    if let Some(ext) = ext {
+        // `.tar.gz` is two extensions deep
+        if TAR_GZ_EXT.contains(&origin_ext.as_str()) {
+            out_path.set_extension("");
+        }
+        out_path.set_extension(ext);
+    }
+    Ok(out_path.to_string_lossy().into_owned())
+}
+
+// a packed folder always becomes a comic book archive named after the folder
+pub fn get_out_pack_path(
+    dir_path: &str,
+    output_path: &str,
+    out_ext: Option<&str>,
+    format: OutputFormat,
+) -> Result<String, MyError> {
+    let ext = out_ext.unwrap_or(match format {
+        OutputFormat::Zip => "cbz",
+        OutputFormat::Tar => "cbt",
+    });
+    let dir_name = Path::new(&dir_path)
+        .file_name()
+        .ok_or(CustomError::new(&format!(
+            "cannot get file_name from {dir_path}"
+        )))?;
+    Ok(resolve_output_root(output_path)?
+        .join(format!("{}.{ext}", dir_name.to_string_lossy()))
         .to_string_lossy()
         .into_owned())
 }
 
-pub fn decode_zip_filename(raw: &[u8]) -> Result<String, MyError> {
+// keep the input extension (and its case) if the container does not change,
+// otherwise pick the zip/tar one, keeping comic `cb?` flavor
+fn rezip_ext(origin_ext: &str, format: OutputFormat) -> Option<String> {
+    let same_container = match format {
+        OutputFormat::Zip => {
+            !SEVENZ_EXT.contains(&origin_ext)
+                && !TAR_EXT.contains(&origin_ext)
+                && !TAR_GZ_EXT.contains(&origin_ext)
+        }
+        OutputFormat::Tar => TAR_EXT.contains(&origin_ext),
+    };
+    if same_container {
+        return None;
+    }
+    let comic = origin_ext.starts_with("cb");
+    let ext = match (format, comic) {
+        (OutputFormat::Zip, true) => "cbz",
+        (OutputFormat::Zip, false) => "zip",
+        (OutputFormat::Tar, true) => "cbt",
+        (OutputFormat::Tar, false) => "tar",
+    };
+    Some(ext.to_string())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArchiveKind {
+    Zip,
+    SevenZ,
+    Tar,
+    TarGz,
+}
+
+// lowercase extension, `tar.gz` counted as one
+pub fn archive_ext(path: &Path) -> String {
+    let file_name = path
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_lowercase();
+    if file_name.ends_with(".tar.gz") {
+        return String::from("tar.gz");
+    }
+    path.extension()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_lowercase()
+}
+
+// match extension case-insensitively, then fall back to sniffing the signature of files
+// that are not a known non-book zip container
+pub fn is_archive_file(path: &Path, archive_ext_set: &[String], sniff: bool) -> bool {
+    is_archive(path, archive_ext_set, sniff, || sniff_archive_kind(path))
+}
+
+// the same for an entry inside an archive, `head` its first `ARCHIVE_SNIFF_LEN` bytes
+pub fn is_archive_entry(name: &str, head: &[u8], archive_ext_set: &[String], sniff: bool) -> bool {
+    is_archive(Path::new(name), archive_ext_set, sniff, || {
+        magic_archive_kind(head)
+    })
+}
+
+fn is_archive(
+    path: &Path,
+    archive_ext_set: &[String],
+    sniff: bool,
+    kind: impl FnOnce() -> Option<ArchiveKind>,
+) -> bool {
+    let ext = archive_ext(path);
+    if archive_ext_set.iter().any(|e| e == &ext) {
+        return true;
+    }
+    sniff && !ZIP_CONTAINER_EXT.contains(&ext.as_str()) && kind().is_some()
+}
+
+// the signature wins over the extension, renamed archives are common
+pub fn archive_kind(path: &Path) -> ArchiveKind {
+    sniff_archive_kind(path).unwrap_or_else(|| {
+        let ext = archive_ext(path);
+        if SEVENZ_EXT.contains(&ext.as_str()) {
+            ArchiveKind::SevenZ
+        } else if TAR_EXT.contains(&ext.as_str()) {
+            ArchiveKind::Tar
+        } else if TAR_GZ_EXT.contains(&ext.as_str()) {
+            ArchiveKind::TarGz
+        } else {
+            ArchiveKind::Zip
+        }
+    })
+}
+
+// gzip is not sniffed, a bare `.gz` says nothing about a tar inside
+pub fn sniff_archive_kind(path: &Path) -> Option<ArchiveKind> {
+    let mut magic = Vec::with_capacity(ARCHIVE_SNIFF_LEN);
+    let f = std::fs::File::open(path).ok()?;
+    f.take(ARCHIVE_SNIFF_LEN as u64)
+        .read_to_end(&mut magic)
+        .ok()?;
+    magic_archive_kind(&magic)
+}
+
+fn magic_archive_kind(magic: &[u8]) -> Option<ArchiveKind> {
+    if magic.starts_with(&SEVENZ_MAGIC) {
+        Some(ArchiveKind::SevenZ)
+    } else if ZIP_MAGIC.iter().any(|m| magic.starts_with(m)) {
+        Some(ArchiveKind::Zip)
+    } else if magic.get(TAR_MAGIC_OFFSET..) == Some(&TAR_MAGIC[..]) {
+        Some(ArchiveKind::Tar)
+    } else {
+        None
+    }
+}
+
+/// Where the decoded name of a zip entry came from.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum NameSource {
+    Ascii,
+    /// the Info-ZIP Unicode Path extra field, its crc matching the raw name
+    UnicodePath,
+    /// general purpose bit 11
+    Utf8Flag,
+    /// forced with `--encoding` or `--encoding-for`
+    Forced(String),
+    /// no flag, but valid utf-8
+    Utf8,
+    /// the encoding decided for the whole archive
+    Archive(String),
+    /// the spec default for unflagged names
+    Cp437,
+    /// chardet's guess, with the encoding it chose
+    Detected(String),
+}
+
+impl std::fmt::Display for NameSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            NameSource::Ascii => write!(f, "ascii"),
+            NameSource::UnicodePath => write!(f, "unicode path extra field"),
+            NameSource::Utf8Flag => write!(f, "utf-8 flag"),
+            NameSource::Forced(encode) => write!(f, "forced {encode}"),
+            NameSource::Utf8 => write!(f, "utf-8 without flag"),
+            NameSource::Archive(encode) => write!(f, "archive-wide {encode}"),
+            NameSource::Cp437 => write!(f, "cp437"),
+            NameSource::Detected(encode) => write!(f, "detected {encode}"),
+        }
+    }
+}
+
+/// How the names of one archive that carry no encoding of their own are decoded.
+#[derive(Clone, Debug)]
+pub struct NameEncoding {
+    /// every such name is decoded with it, whatever it looks like
+    pub forced: Option<String>,
+    /// decided from all names of the archive, see `archive_encoding`
+    pub archive: Option<String>,
+    /// legacy encodings in order of preference, the first one is the last resort
+    pub candidates: Vec<String>,
+}
+
+impl Default for NameEncoding {
+    fn default() -> Self {
+        NameEncoding {
+            forced: None,
+            archive: None,
+            candidates: LEGACY_ENCODINGS
+                .iter()
+                .map(|label| label.to_string())
+                .collect(),
+        }
+    }
+}
+
+/// How well one candidate decodes the undecided names of an archive.
+#[derive(Clone, Debug, Default)]
+pub struct EncodingScore {
+    pub label: String,
+    /// names that round-trip through it
+    pub clean: usize,
+    /// per mille of their non-ascii chars in the expected scripts
+    pub script: usize,
+}
+
+/// What `archive_encoding` decided and what it went on.
+#[derive(Clone, Debug, Default)]
+pub struct ArchiveEncoding {
+    pub chosen: Option<String>,
+    /// names nothing else decides
+    pub undecided: usize,
+    /// chardet on all of them at once
+    pub guess: String,
+    pub confidence: f32,
+    pub scores: Vec<EncodingScore>,
+}
+
+// a valid unicode path extra field first, then general purpose bit 11, then a forced
+// encoding, then the encoding decided for the whole archive (see `archive_encoding`), then
+// what the spec says for unflagged names (cp437) unless the bytes are one of the candidate
+// encodings, only those go to chardet
+pub fn decode_zip_filename(
+    raw: &[u8],
+    utf8_flag: bool,
+    extra: &[u8],
+    encoding: &NameEncoding,
+) -> Result<(String, NameSource), MyError> {
+    if raw.is_ascii() && find_extra_field(extra, UNICODE_PATH_ID).is_none() {
+        return Ok((String::from_utf8_lossy(raw).into_owned(), NameSource::Ascii));
+    }
+    if let Some(name) = unicode_path(raw, extra) {
+        return Ok((name, NameSource::UnicodePath));
+    }
+    // a flag on a name that is not utf-8 is a lie, `inspect` shows those
+    if utf8_flag {
+        if let Ok(name) = std::str::from_utf8(raw) {
+            return Ok((name.to_string(), NameSource::Utf8Flag));
+        }
+    }
+    if raw.is_ascii() {
+        return Ok((String::from_utf8_lossy(raw).into_owned(), NameSource::Ascii));
+    }
+    if let Some(label) = &encoding.forced {
+        let decoder = encoding_from_whatwg_label(label)
+            .ok_or_else(|| CustomError::new(&format!("unknown encoding {label}")))?;
+        let name = decoder
+            .decode(raw, DecoderTrap::Replace)
+            .map_err(|e| CustomError::new(&format!("decode failed, encode: {label}: {e}")))?;
+        return Ok((name, NameSource::Forced(label.clone())));
+    }
+    // tools that forget the flag mostly write utf-8, which legacy names rarely happen to be
+    if let Ok(name) = std::str::from_utf8(raw) {
+        return Ok((name.to_string(), NameSource::Utf8));
+    }
+    if let Some(label) = &encoding.archive {
+        if let Some(name) = round_trip(raw, label) {
+            return Ok((name, NameSource::Archive(label.clone())));
+        }
+    }
+    let legacy = encoding.candidates.iter().any(|label| {
+        encoding_from_whatwg_label(label)
+            .is_some_and(|decoder| decoder.decode(raw, DecoderTrap::Strict).is_ok())
+    });
+    if !legacy {
+        return Ok((decode_cp437(raw), NameSource::Cp437));
+    }
+    detect_filename(
+        raw,
+        encoding
+            .candidates
+            .first()
+            .map_or(FALLBACK_ENCODING, String::as_str),
+    )
+}
+
+// one encoding for every name of an archive that nothing else decides (not utf-8, no
+// unicode path). chardet gets all of them at once, short names alone give it too little to
+// go on, its guess joins the candidates. Only a candidate every one of those names
+// round-trips through is chosen, none otherwise and the names are decided one by one.
+// Among those, scored by how much of what they decode to is in the scripts names are
+// expected in; ties go to the earlier candidate
+pub fn archive_encoding<'a>(
+    names: impl IntoIterator<Item = (&'a [u8], &'a [u8])>,
+    candidates: &[String],
+) -> ArchiveEncoding {
+    // a flag on a name that is not utf-8 decides nothing either
+    let undecided: Vec<&[u8]> = names
+        .into_iter()
+        .filter(|(raw, extra)| {
+            std::str::from_utf8(raw).is_err() && unicode_path(raw, extra).is_none()
+        })
+        .map(|(raw, _)| raw)
+        .collect();
+    if undecided.is_empty() {
+        return ArchiveEncoding::default();
+    }
+
+    let joined = undecided.join(&b'/');
+    let (encode, confidence, _) = chardet::detect(&joined);
+    let mut labels = candidates.to_vec();
+    if !encode.is_empty() {
+        let guess = chardet::charset2encoding(&encode);
+        if !labels.iter().any(|label| label.eq_ignore_ascii_case(guess)) {
+            labels.push(guess.to_string());
+        }
+    }
+
+    let mut decision = ArchiveEncoding {
+        undecided: undecided.len(),
+        guess: encode,
+        confidence,
+        ..Default::default()
+    };
+    let mut best: Option<usize> = None;
+    for label in labels {
+        let (mut clean, mut matched, mut total) = (0, 0, 0);
+        for raw in &undecided {
+            if let Some(name) = round_trip(raw, &label) {
+                clean += 1;
+                for c in name.chars().filter(|c| !c.is_ascii()) {
+                    total += 1;
+                    if in_expected_script(c, &label) {
+                        matched += 1;
+                    }
+                }
+            }
+        }
+        // per mille, names of different lengths are compared by share
+        let script = matched * 1000 / total.max(1);
+        if clean == decision.undecided && best.is_none_or(|best| script > best) {
+            best = Some(script);
+            decision.chosen = Some(label.clone());
+        }
+        decision.scores.push(EncodingScore {
+            label,
+            clean,
+            script,
+        });
+    }
+    decision
+}
+
+// whether a decoded char comes from the part of the encoding names are usually written
+// in, see `EXPECTED_LEAD_BYTES`; labels without an entry (a latin code page from chardet)
+// take any letter
+fn in_expected_script(c: char, label: &str) -> bool {
+    let label = label.to_lowercase();
+    let Some((_, ranges)) = EXPECTED_LEAD_BYTES.iter().find(|(l, _)| *l == label) else {
+        return c.is_alphabetic();
+    };
+    let Some(encoding) = encoding_from_whatwg_label(&label) else {
+        return false;
+    };
+    match encoding.encode(&c.to_string(), EncoderTrap::Strict) {
+        Ok(bytes) => bytes
+            .first()
+            .is_some_and(|lead| ranges.iter().any(|(lo, hi)| (lo..=hi).contains(&lead))),
+        Err(_) => false,
+    }
+}
+
+// the decoded name, if it encodes back to the very same bytes
+pub fn round_trip(raw: &[u8], label: &str) -> Option<String> {
+    let encoding = encoding_from_whatwg_label(label)?;
+    let name = encoding.decode(raw, DecoderTrap::Strict).ok()?;
+    match encoding.encode(&name, EncoderTrap::Strict) {
+        Ok(bytes) if bytes == raw => Some(name),
+        _ => None,
+    }
+}
+
+// version 1, crc32 of the raw name, the utf-8 name. A crc that does not match means the
+// name was changed by a tool that did not know the field, the copy is stale then
+pub fn unicode_path(raw: &[u8], extra: &[u8]) -> Option<String> {
+    let data = find_extra_field(extra, UNICODE_PATH_ID)?;
+    if data.len() < 5 || data[0] != 1 {
+        return None;
+    }
+    let crc = u32::from_le_bytes([data[1], data[2], data[3], data[4]]);
+    if crc != crc32fast::hash(raw) {
+        return None;
+    }
+    String::from_utf8(data[5..].to_vec()).ok()
+}
+
+fn decode_cp437(raw: &[u8]) -> String {
+    raw.iter()
+        .map(|&b| match b {
+            0..=0x7f => b as char,
+            _ => CP437_HIGH[(b - 0x80) as usize],
+        })
+        .collect()
+}
+
+// statistical detection for names nothing else could settle
+fn detect_filename(raw: &[u8], fallback: &str) -> Result<(String, NameSource), MyError> {
     let (mut encode, confidence, _) = chardet::detect(raw);
 
     encode = if encode == "" {
-        println!(
-            "{}",
-            Chalk::new().light_red().string(
-                &(String::from("chardet::detect gives empty encode, fallback to ")
-                    + FALLBACK_ENCODING)
-            )
-        );
-        String::from(FALLBACK_ENCODING)
+        String::from(fallback)
     } else {
         encode
     };
@@ -70,36 +509,152 @@ pub fn decode_zip_filename(raw: &[u8]) -> Result<String, MyError> {
             ))
         })?;
 
-    if encode != ASCII && encode != UTF8 {
-        // encoding debug
-        println!(
-              "entry encode: {encode}, confidence: {confidence}, raw: {}, decoded: {}, GB18030: {}, ISO-2022-JP: {}, Shift_JIS: {}",
-              Chalk::new()
-                  .yellow()
-                  .string(&String::from_utf8_lossy(raw)),
-              Chalk::new().green().string(&decoded_string.clone()),
-              Chalk::new()
-                  .cyan()
-                  .string(&encoding_from_whatwg_label("GB18030")
-                  .unwrap_or(encoding_from_whatwg_label("UTF-8").unwrap())
-                  .decode(&raw, DecoderTrap::Ignore)
-                  .unwrap_or(String::from("?"))),
-              Chalk::new()
-                  .magenta()
-                  .string(&encoding_from_whatwg_label("ISO-2022-JP")
-                  .unwrap_or(encoding_from_whatwg_label("UTF-8").unwrap())
-                  .decode(&raw, DecoderTrap::Ignore)
-                  .unwrap_or(String::from("?"))),
-              Chalk::new()
-                  .blue()
-                  .string(&encoding_from_whatwg_label("Shift_JIS")
-                  .unwrap_or(encoding_from_whatwg_label("UTF-8").unwrap())
-                  .decode(&raw, DecoderTrap::Ignore)
-                  .unwrap_or(String::from("?")))
-          );
-    }
-
-    return Ok(decoded_string);
+    Ok((decoded_string, NameSource::Detected(encode)))
+}
+
+// passwords from the archive's directory list first, then the global file
+pub fn load_passwords(
+    archive_path: &Path,
+    password_file: Option<&str>,
+) -> Result<Vec<String>, MyError> {
+    let mut passwords = vec![];
+    if let Some(dir) = archive_path.parent() {
+        let dir_list = dir.join(PASSWORD_LIST_FILE);
+        if dir_list.is_file() {
+            passwords.extend(read_password_list(&dir_list)?);
+        }
+    }
+    if let Some(password_file) = password_file {
+        passwords.extend(read_password_list(Path::new(password_file))?);
+    }
+    Ok(passwords)
+}
+
+fn read_password_list(path: &Path) -> Result<Vec<String>, MyError> {
+    // spaces may be part of a password, only line endings are stripped
+    Ok(std::fs::read_to_string(path)?
+        .lines()
+        .map(|line| line.trim_end_matches('\r').to_string())
+        .filter(|line| !line.is_empty())
+        .collect())
+}
+
+// content that gains nothing from another compression pass
+pub fn is_compressed_content(head: &[u8]) -> bool {
+    COMPRESSED_MAGIC
+        .iter()
+        .any(|(offset, magic)| head.get(*offset..offset + magic.len()) == Some(*magic))
+}
+
+// zip stores a timezone-less local time, read and written as such like every other zip tool
+// does. Otherwise the extended timestamp written next to it would not agree with it
+pub fn zip_datetime(time: SystemTime) -> Option<zip::DateTime> {
+    use chrono::{Datelike, Timelike};
+
+    let local = chrono::DateTime::<chrono::Local>::from(time);
+    zip::DateTime::from_date_and_time(
+        u16::try_from(local.year()).ok()?,
+        local.month() as u8,
+        local.day() as u8,
+        local.hour() as u8,
+        local.minute() as u8,
+        local.second() as u8,
+    )
+    .ok()
+}
+
+// inverse of zip_datetime, a time skipped by a DST change has no instant
+pub fn dos_datetime_to_system_time(
+    year: u16,
+    month: u8,
+    day: u8,
+    hour: u8,
+    minute: u8,
+    second: u8,
+) -> Option<SystemTime> {
+    use chrono::TimeZone;
+
+    let local = chrono::NaiveDate::from_ymd_opt(year as i32, month as u32, day as u32)?
+        .and_hms_opt(hour as u32, minute as u32, second as u32)?;
+    Some(chrono::Local.from_local_datetime(&local).earliest()?.into())
+}
+
+// walk the (id, size, data) records of a raw zip extra field
+pub fn find_extra_field(extra: &[u8], id: u16) -> Option<&[u8]> {
+    let mut rest = extra;
+    while rest.len() >= 4 {
+        let field_id = u16::from_le_bytes([rest[0], rest[1]]);
+        let size = u16::from_le_bytes([rest[2], rest[3]]) as usize;
+        let data = rest.get(4..4 + size)?;
+        if field_id == id {
+            return Some(data);
+        }
+        rest = &rest[4 + size..];
+    }
+    None
+}
+
+// mtime of an extended timestamp ("UT") extra field, to the second and timezone-free
+pub fn extended_timestamp(extra: &[u8]) -> Option<SystemTime> {
+    let data = find_extra_field(extra, EXTENDED_TIMESTAMP_ID)?;
+    // flags bit 0: mtime present, it always comes first
+    if data.len() < 5 || data[0] & 1 == 0 {
+        return None;
+    }
+    let secs = i32::from_le_bytes([data[1], data[2], data[3], data[4]]);
+    if secs >= 0 {
+        Some(UNIX_EPOCH + Duration::from_secs(secs as u64))
+    } else {
+        UNIX_EPOCH.checked_sub(Duration::from_secs(secs.unsigned_abs() as u64))
+    }
+}
+
+pub fn extended_timestamp_field(mtime: SystemTime) -> Option<[u8; 5]> {
+    let secs = match mtime.duration_since(UNIX_EPOCH) {
+        Ok(d) => i32::try_from(d.as_secs()).ok()?,
+        Err(e) => -i32::try_from(e.duration().as_secs()).ok()?,
+    };
+    let mut data = [1u8; 5];
+    data[1..].copy_from_slice(&secs.to_le_bytes());
+    Some(data)
+}
+
+// carry an archive entry's mtime and permission bits onto an extracted file as they are,
+// the writers take its mode from there. Removing it only takes the temp dir's permissions
+pub fn restore_file_attrs(
+    path: &Path,
+    mtime: Option<SystemTime>,
+    mode: Option<u32>,
+) -> Result<(), MyError> {
+    #[cfg(unix)]
+    if let Some(mode) = mode {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode & 0o777))?;
+    }
+    #[cfg(not(unix))]
+    let _ = mode;
+    if let Some(mtime) = mtime {
+        filetime::set_file_mtime(path, filetime::FileTime::from_system_time(mtime))?;
+    }
+    Ok(())
+}
+
+// an extracted file we have no read permission for is opened all the same, with u+r for
+// the open only. Its metadata still has the mode of the entry
+pub fn open_extracted(path: &Path) -> std::io::Result<std::fs::File> {
+    match std::fs::File::open(path) {
+        #[cfg(unix)]
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+            use std::os::unix::fs::PermissionsExt;
+            let permissions = std::fs::metadata(path)?.permissions();
+            let readable = std::fs::Permissions::from_mode(permissions.mode() | 0o400);
+            std::fs::set_permissions(path, readable)?;
+            let file = std::fs::File::open(path);
+            std::fs::set_permissions(path, permissions)?;
+            file
+        }
+        file => file,
+    }
 }
 
 pub fn get_file_ext_or_itself(filename: &str) -> String {
@@ -128,3 +683,1108 @@ pub fn pad_end(s: &str, width: usize, pad: char) -> String {
         s.to_string()
     }
 }
+
+// `?` one char, `*` any run within a path segment, `**` any run across segments
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    fn matches(p: &[char], t: &[char]) -> bool {
+        match p {
+            [] => t.is_empty(),
+            ['*', '*', rest @ ..] => (0..=t.len()).any(|i| matches(rest, &t[i..])),
+            ['*', rest @ ..] => (0..=t.len())
+                .take_while(|&i| i == 0 || t[i - 1] != '/')
+                .any(|i| matches(rest, &t[i..])),
+            ['?', rest @ ..] => !t.is_empty() && t[0] != '/' && matches(rest, &t[1..]),
+            [c, rest @ ..] => t.first() == Some(c) && matches(rest, &t[1..]),
+        }
+    }
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    matches(&pattern, &text)
+}
+
+// `2.jpg` before `10.jpg`: digit runs compare by value, the rest case-insensitively
+pub fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let mut a_chars = a.chars().peekable();
+    let mut b_chars = b.chars().peekable();
+    loop {
+        match (a_chars.peek().copied(), b_chars.peek().copied()) {
+            (None, None) => return a.cmp(b),
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let x_run = take_digits(&mut a_chars);
+                let y_run = take_digits(&mut b_chars);
+                let x_num = x_run.trim_start_matches('0');
+                let y_num = y_run.trim_start_matches('0');
+                let ord = x_num.len().cmp(&y_num.len()).then_with(|| x_num.cmp(y_num));
+                if ord != Ordering::Equal {
+                    return ord;
+                }
+            }
+            (Some(x), Some(y)) => {
+                let ord = x.to_lowercase().cmp(y.to_lowercase());
+                if ord != Ordering::Equal {
+                    return ord;
+                }
+                a_chars.next();
+                b_chars.next();
+            }
+        }
+    }
+}
+
+fn take_digits(chars: &mut std::iter::Peekable<std::str::Chars>) -> String {
+    let mut run = String::new();
+    while let Some(c) = chars.next_if(|c| c.is_ascii_digit()) {
+        run.push(c);
+    }
+    run
+}
+
+// rename the pages of every folder under `dir` to a zero-padded sequence in natural order,
+// returns (old, new) paths relative to `dir`
+pub fn renumber_pages(dir: &Path) -> Result<Vec<(PathBuf, PathBuf)>, MyError> {
+    let mut renamed = vec![];
+    // the resource forks in `__MACOSX` are named like pages, and never part of the book
+    let folders: Vec<PathBuf> = WalkDir::new(dir)
+        .into_iter()
+        .filter_entry(|e| e.file_name() != TRASH_DIR)
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_dir())
+        .map(|e| e.into_path())
+        .collect();
+    for folder in folders {
+        let mut pages: Vec<PathBuf> = std::fs::read_dir(&folder)?
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|path| path.is_file() && is_page(path))
+            .collect();
+        pages.sort_by(|a, b| {
+            natural_cmp(
+                &a.file_name().unwrap_or_default().to_string_lossy(),
+                &b.file_name().unwrap_or_default().to_string_lossy(),
+            )
+        });
+
+        let width = pages.len().to_string().len().max(PAGE_NUMBER_WIDTH);
+        let mut staged = vec![];
+        // two passes, a new name may still be taken by a page not renamed yet
+        for (i, page) in pages.iter().enumerate() {
+            let tmp_path = page.with_file_name(format!(".renumber_{i}"));
+            std::fs::rename(page, &tmp_path)?;
+            staged.push(tmp_path);
+        }
+        for (i, (page, tmp_path)) in pages.iter().zip(staged).enumerate() {
+            let mut new_name = pad_start(&(i + 1).to_string(), width, '0');
+            if let Some(ext) = page.extension() {
+                new_name = new_name + "." + &ext.to_string_lossy();
+            }
+            let new_path = page.with_file_name(new_name);
+            std::fs::rename(&tmp_path, &new_path)?;
+            if &new_path != page {
+                renamed.push((
+                    page.strip_prefix(dir).unwrap_or(page).to_path_buf(),
+                    new_path
+                        .strip_prefix(dir)
+                        .unwrap_or(&new_path)
+                        .to_path_buf(),
+                ));
+            }
+        }
+    }
+    Ok(renamed)
+}
+
+pub fn is_page(path: &Path) -> bool {
+    mime_guess::from_path(path)
+        .first()
+        .is_some_and(|mime| mime.type_() == mime_guess::mime::IMAGE)
+}
+
+// pull the book out of a lone top-level folder (`Title/001.jpg` -> `001.jpg`) and, with
+// `chapters`, every sub folder into a name prefix (`c01/001.jpg` -> `c01_001.jpg`),
+// returns (old, new) paths relative to `dir`
+pub fn flatten_dir(dir: &Path, chapters: bool) -> Result<Vec<(PathBuf, PathBuf)>, MyError> {
+    // `Title/Title/001.jpg` is as redundant as `Title/001.jpg`
+    let mut top = PathBuf::new();
+    loop {
+        let entries = book_entries(&dir.join(&top))?;
+        match entries.as_slice() {
+            [only] if only.is_dir() => top.push(only.file_name().unwrap_or_default()),
+            _ => break,
+        }
+    }
+    let root = dir.join(&top);
+
+    let mut labels: HashMap<PathBuf, String> = HashMap::new();
+    if chapters {
+        for folder in WalkDir::new(&root)
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().is_dir())
+        {
+            let mut sub_folders: Vec<PathBuf> = book_entries(folder.path())?
+                .into_iter()
+                .filter(|path| path.is_dir())
+                .collect();
+            sub_folders.sort_by(|a, b| {
+                natural_cmp(
+                    &a.file_name().unwrap_or_default().to_string_lossy(),
+                    &b.file_name().unwrap_or_default().to_string_lossy(),
+                )
+            });
+            let width = sub_folders
+                .len()
+                .to_string()
+                .len()
+                .max(CHAPTER_NUMBER_WIDTH);
+            for (i, sub_folder) in sub_folders.into_iter().enumerate() {
+                labels.insert(
+                    sub_folder,
+                    format!("c{}", pad_start(&(i + 1).to_string(), width, '0')),
+                );
+            }
+        }
+    }
+
+    let mut planned = vec![];
+    let mut taken = HashSet::new();
+    for file in WalkDir::new(dir)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file())
+    {
+        let old = file
+            .path()
+            .strip_prefix(dir)
+            .unwrap_or(file.path())
+            .to_path_buf();
+        if old.components().any(|c| c.as_os_str() == TRASH_DIR) {
+            continue;
+        }
+        let Ok(in_root) = file.path().strip_prefix(&root) else {
+            taken.insert(old);
+            continue;
+        };
+        let new = match in_root.parent() {
+            Some(parent) if chapters && !parent.as_os_str().is_empty() => {
+                let mut prefix = String::new();
+                let mut folder = root.clone();
+                for component in parent.components() {
+                    folder.push(component);
+                    prefix = prefix + labels.get(&folder).map_or("", |label| label) + "_";
+                }
+                PathBuf::from(prefix + &file.file_name().to_string_lossy())
+            }
+            _ => in_root.to_path_buf(),
+        };
+        planned.push((old, new));
+    }
+
+    // a file already at its new path keeps it, wherever the walk met it
+    for (old, new) in &planned {
+        if old == new {
+            taken.insert(new.clone());
+        }
+    }
+    let mut moves = vec![];
+    for (old, new) in planned {
+        if old == new {
+            continue;
+        }
+        if !taken.insert(new.clone()) {
+            eprintln!(
+                "[flatten_dir] {:?} -> {:?} already taken, keep it",
+                old, new
+            );
+            taken.insert(old);
+            continue;
+        }
+        moves.push((old, new));
+    }
+
+    // through a staging folder, a new path may still be an old folder (`Title/Title/`)
+    let staging = dir.join(".flatten");
+    for (old, new) in &moves {
+        let staged = staging.join(new);
+        if let Some(parent) = staged.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::rename(dir.join(old), staged)?;
+    }
+    for folder in WalkDir::new(dir)
+        .contents_first(true)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_dir() && e.path() != dir && !e.path().starts_with(&staging))
+    {
+        // only the emptied ones go, the rest fails harmlessly
+        let _ = std::fs::remove_dir(folder.path());
+    }
+    for (_, new) in &moves {
+        let new_path = dir.join(new);
+        if let Some(parent) = new_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::rename(staging.join(new), new_path)?;
+    }
+    if staging.exists() {
+        std::fs::remove_dir_all(&staging)?;
+    }
+
+    Ok(moves)
+}
+
+// folder entries that count as content of the book, the trash is left out here as it is
+// out of the archive
+fn book_entries(dir: &Path) -> Result<Vec<PathBuf>, MyError> {
+    Ok(std::fs::read_dir(dir)?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|path| {
+            !is_trash(
+                Path::new(path.file_name().unwrap_or_default()),
+                path.is_dir(),
+            )
+        })
+        .collect())
+}
+
+// `__MACOSX` and everything in it, and files with a trash extension (`*.url`, `*.db`...).
+// Never part of a book, whether extracted or piped through
+pub fn is_trash(path: &Path, is_dir: bool) -> bool {
+    path.components().any(|c| c.as_os_str() == TRASH_DIR)
+        || (!is_dir
+            && TRASH_EXT
+                .iter()
+                .any(|ext| path.extension().unwrap_or_default().to_string_lossy() == *ext))
+}
+
+// sha256 of a source (archive bytes, or the pages of a packed folder) and the settings
+// its book is made with
+pub fn fingerprint(source: &Path, settings: &str) -> Result<String, MyError> {
+    let mut hasher = Sha256::new();
+    if source.is_dir() {
+        let mut files: Vec<PathBuf> = std::fs::read_dir(source)?
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|path| path.is_file())
+            .collect();
+        files.sort();
+        for file in files {
+            hasher.update(
+                file.file_name()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .as_bytes(),
+            );
+            std::io::copy(&mut std::fs::File::open(&file)?, &mut hasher)?;
+        }
+    } else {
+        std::io::copy(&mut std::fs::File::open(source)?, &mut hasher)?;
+    }
+    hasher.update(settings.as_bytes());
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+// the fingerprint a book was written with, `None` if it was not made by us
+pub fn read_fingerprint(archive: &Path) -> Option<String> {
+    let comment = match sniff_archive_kind(archive)? {
+        ArchiveKind::Zip => {
+            let zip = zip::ZipArchive::new(std::fs::File::open(archive).ok()?).ok()?;
+            String::from_utf8_lossy(zip.comment()).into_owned()
+        }
+        ArchiveKind::Tar => {
+            // a pax global header in front of everything
+            let mut tar = tar::Archive::new(std::fs::File::open(archive).ok()?);
+            let mut entry = tar.entries().ok()?.next()?.ok()?;
+            if !entry.header().entry_type().is_pax_global_extensions() {
+                return None;
+            }
+            let mut records = String::new();
+            entry.read_to_string(&mut records).ok()?;
+            records
+                .lines()
+                .filter_map(|record| record.split_once(' ')?.1.split_once('='))
+                .find(|(key, _)| *key == "comment")?
+                .1
+                .to_string()
+        }
+        _ => return None,
+    };
+    comment
+        .strip_prefix(FINGERPRINT_PREFIX)
+        .map(|fingerprint| fingerprint.to_string())
+}
+
+// first free `name_1.ext`, `name_2.ext`... next to an existing output, `claim` takes it
+// unless another book got there first
+pub fn numbered_path(dest_file: &str, claim: impl Fn(&Path) -> bool) -> String {
+    let path = Path::new(dest_file);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let ext = path
+        .extension()
+        .map(|ext| format!(".{}", ext.to_string_lossy()))
+        .unwrap_or_default();
+    let mut i = 1;
+    loop {
+        let candidate = path.with_file_name(format!("{stem}_{i}{ext}"));
+        if !candidate.exists() && claim(&candidate) {
+            return candidate.to_string_lossy().into_owned();
+        }
+        i += 1;
+    }
+}
+
+// hidden sibling of an output, renamed over it once complete
+pub fn partial_path(dst_file: &Path) -> PathBuf {
+    dst_file.with_file_name(format!(
+        ".{}{PARTIAL_SUFFIX}",
+        dst_file.file_name().unwrap_or_default().to_string_lossy()
+    ))
+}
+
+pub fn commit_partial(partial: &Path, dst_file: &Path) -> Result<(), MyError> {
+    std::fs::rename(partial, dst_file)?;
+    // the rename itself only survives a crash once its directory is synced
+    #[cfg(unix)]
+    {
+        let parent = match dst_file.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        std::fs::File::open(parent)?.sync_all()?;
+    }
+    Ok(())
+}
+
+// outputs a crashed run was still writing, removed before a new run starts. Only those
+// not modified for `max_age`, another run may be writing into the same output
+pub fn clean_partials(dir: &Path, max_age: Duration) -> Vec<PathBuf> {
+    let stale = |entry: &walkdir::DirEntry| {
+        entry
+            .metadata()
+            .ok()
+            .and_then(|meta| meta.modified().ok())
+            .and_then(|modified| modified.elapsed().ok())
+            .is_some_and(|age| age >= max_age)
+    };
+    let mut removed = vec![];
+    for entry in WalkDir::new(dir)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file())
+        .filter(|e| e.file_name().to_string_lossy().ends_with(PARTIAL_SUFFIX))
+        .filter(stale)
+    {
+        match std::fs::remove_file(entry.path()) {
+            Ok(_) => removed.push(entry.into_path()),
+            Err(e) => eprintln!("remove {:?} failed: {e}", entry.path()),
+        }
+    }
+    removed
+}
+
+// both exist and resolve to the same path
+pub fn same_file(a: &Path, b: &Path) -> bool {
+    match (std::fs::canonicalize(a), std::fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+// io::copy with a caller owned buffer, reused for every file of a book
+pub fn copy_with_buffer<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    buffer: &mut [u8],
+) -> Result<u64, MyError>
+where
+    R: Read + ?Sized,
+    W: std::io::Write + ?Sized,
+{
+    let mut copied = 0;
+    loop {
+        let n = match reader.read(buffer) {
+            Ok(0) => return Ok(copied),
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(MyError::Io(e)),
+        };
+        writer.write_all(&buffer[..n])?;
+        copied += n as u64;
+    }
+}
+
+// what a book is expected to hold in memory at most: its source size (archive, or the
+// pages of a folder) plus the copy buffer, plus the rgba pixels of every page that gets
+// converted, those are decoded side by side. Dimensions come from the page headers of
+// folders and zips; 7z and tar are not listed up front, all of their size counts as pages
+// of unknown dimensions
+pub fn book_cost(source: &Path) -> u64 {
+    let mut cost = COPY_BUF_LEN as u64;
+    if source.is_dir() {
+        for entry in std::fs::read_dir(source).into_iter().flatten().flatten() {
+            let path = entry.path();
+            let size = match entry.metadata() {
+                Ok(metadata) if metadata.is_file() => metadata.len(),
+                _ => continue,
+            };
+            cost += size;
+            if is_transformed(&path) {
+                let mut head = Vec::with_capacity(PROBE_LEN);
+                if let Ok(file) = std::fs::File::open(&path) {
+                    let _ = file.take(PROBE_LEN as u64).read_to_end(&mut head);
+                }
+                cost += decode_cost(&head, size);
+            }
+        }
+        return cost;
+    }
+
+    let size = std::fs::metadata(source).map(|m| m.len()).unwrap_or(0);
+    cost += size;
+    let zip = match archive_kind(source) {
+        ArchiveKind::Zip => std::fs::File::open(source)
+            .ok()
+            .and_then(|f| zip::ZipArchive::new(f).ok()),
+        _ => None,
+    };
+    let Some(mut zip) = zip else {
+        return cost.saturating_add(size.saturating_mul(DECODE_MULTIPLIER));
+    };
+    for i in 0..zip.len() {
+        let size = match zip.by_index_raw(i) {
+            Ok(file) if is_transformed(Path::new(file.name())) => file.size(),
+            _ => continue,
+        };
+        // encrypted entries cannot be probed, their size still counts
+        let mut head = Vec::with_capacity(PROBE_LEN);
+        if let Ok(file) = zip.by_index(i) {
+            let _ = file.take(PROBE_LEN as u64).read_to_end(&mut head);
+        }
+        cost = cost.saturating_add(decode_cost(&head, size));
+    }
+    cost
+}
+
+// pages the convert step decodes, see `TRANSFORM_EXT`
+fn is_transformed(path: &Path) -> bool {
+    let ext = path.extension().unwrap_or_default().to_string_lossy();
+    TRANSFORM_EXT.iter().any(|e| ext == *e)
+}
+
+fn decode_cost(head: &[u8], size: u64) -> u64 {
+    match image_dimensions(head) {
+        Some((width, height)) => width as u64 * height as u64 * 4,
+        None => size.saturating_mul(DECODE_MULTIPLIER),
+    }
+}
+
+// (width, height) from the header of a png, gif, bmp, webp or jpeg
+pub fn image_dimensions(head: &[u8]) -> Option<(u32, u32)> {
+    let u16_le = |at: usize| Some(u16::from_le_bytes(head.get(at..at + 2)?.try_into().ok()?));
+    let u16_be = |at: usize| Some(u16::from_be_bytes(head.get(at..at + 2)?.try_into().ok()?));
+    let u24_le = |at: usize| {
+        let b = head.get(at..at + 3)?;
+        Some(u32::from_le_bytes([b[0], b[1], b[2], 0]))
+    };
+    let u32_le = |at: usize| Some(u32::from_le_bytes(head.get(at..at + 4)?.try_into().ok()?));
+    let u32_be = |at: usize| Some(u32::from_be_bytes(head.get(at..at + 4)?.try_into().ok()?));
+
+    if head.starts_with(b"\x89PNG\r\n\x1a\n") {
+        return Some((u32_be(16)?, u32_be(20)?));
+    }
+    if head.starts_with(b"GIF8") {
+        return Some((u16_le(6)? as u32, u16_le(8)? as u32));
+    }
+    if head.starts_with(b"BM") {
+        // negative heights are top-down bitmaps
+        return Some((u32_le(18)?, (u32_le(22)? as i32).unsigned_abs()));
+    }
+    if head.starts_with(b"RIFF") && head.get(8..12) == Some(b"WEBP") {
+        return match head.get(12..16)? {
+            b"VP8 " => Some(((u16_le(26)? & 0x3fff) as u32, (u16_le(28)? & 0x3fff) as u32)),
+            b"VP8L" => {
+                let bits = u32_le(21)?;
+                Some(((bits & 0x3fff) + 1, ((bits >> 14) & 0x3fff) + 1))
+            }
+            b"VP8X" => Some((u24_le(24)? + 1, u24_le(27)? + 1)),
+            _ => None,
+        };
+    }
+    if head.starts_with(b"\xFF\xD8") {
+        let mut at = 2;
+        loop {
+            if *head.get(at)? != 0xFF {
+                return None;
+            }
+            let marker = *head.get(at + 1)?;
+            match marker {
+                // fill bytes
+                0xFF => at += 1,
+                // markers without a length
+                0x01 | 0xD0..=0xD7 => at += 2,
+                0xC0..=0xCF if !matches!(marker, 0xC4 | 0xC8 | 0xCC) => {
+                    return Some((u16_be(at + 7)? as u32, u16_be(at + 5)? as u32));
+                }
+                _ => at += 2 + u16_be(at + 2)? as usize,
+            }
+        }
+    }
+    None
+}
+
+// high-water mark of the resident set of this process
+#[cfg(unix)]
+pub fn peak_memory() -> Option<u64> {
+    let mut usage = std::mem::MaybeUninit::<libc::rusage>::zeroed();
+    // SAFETY: getrusage only fills in the struct it is given
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, usage.as_mut_ptr()) } != 0 {
+        return None;
+    }
+    // SAFETY: initialized by the successful call above
+    let max_rss = unsafe { usage.assume_init() }.ru_maxrss as u64;
+    // bytes on macOS, kilobytes everywhere else
+    if cfg!(target_os = "macos") {
+        Some(max_rss)
+    } else {
+        Some(max_rss * 1024)
+    }
+}
+
+#[cfg(not(unix))]
+pub fn peak_memory() -> Option<u64> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sorted(names: &[&str]) -> Vec<String> {
+        let mut names: Vec<String> = names.iter().map(|n| n.to_string()).collect();
+        names.sort_by(|a, b| natural_cmp(a, b));
+        names
+    }
+
+    #[test]
+    fn natural_cmp_digit_runs() {
+        assert_eq!(
+            sorted(&[
+                "10.jpg",
+                "2.jpg",
+                "1.jpg",
+                "c10_3.jpg",
+                "c2_12.jpg",
+                "c2_3.jpg"
+            ]),
+            [
+                "1.jpg",
+                "2.jpg",
+                "10.jpg",
+                "c2_3.jpg",
+                "c2_12.jpg",
+                "c10_3.jpg"
+            ]
+        );
+        // longer than any integer type
+        assert_eq!(
+            natural_cmp("123456789012345678901234567890", "99"),
+            Ordering::Greater
+        );
+    }
+
+    #[test]
+    fn natural_cmp_leading_zeros() {
+        assert_eq!(natural_cmp("002.jpg", "10.jpg"), Ordering::Less);
+        assert_eq!(natural_cmp("0010.jpg", "9.jpg"), Ordering::Greater);
+        // same value, the tie is broken so the order stays total
+        assert_ne!(natural_cmp("01.jpg", "1.jpg"), Ordering::Equal);
+        assert_eq!(natural_cmp("01.jpg", "01.jpg"), Ordering::Equal);
+    }
+
+    #[test]
+    fn natural_cmp_mixed_case() {
+        assert_eq!(
+            sorted(&["b.jpg", "A.jpg", "a2.jpg", "C.jpg"]),
+            ["A.jpg", "a2.jpg", "b.jpg", "C.jpg"]
+        );
+        assert_ne!(natural_cmp("a.jpg", "A.jpg"), Ordering::Equal);
+    }
+
+    #[test]
+    fn natural_cmp_non_ascii() {
+        assert_eq!(
+            sorted(&["第10話.jpg", "第2話.jpg", "第1話.jpg"]),
+            ["第1話.jpg", "第2話.jpg", "第10話.jpg"]
+        );
+        assert_eq!(natural_cmp("Émile 2", "émile 10"), Ordering::Less);
+        // full-width digits are not digit runs, they compare as text
+        assert_eq!(natural_cmp("１０", "２"), Ordering::Less);
+    }
+
+    #[test]
+    fn image_dimensions_from_headers() {
+        let mut png = b"\x89PNG\r\n\x1a\n\x00\x00\x00\x0dIHDR".to_vec();
+        png.extend_from_slice(&1200u32.to_be_bytes());
+        png.extend_from_slice(&1800u32.to_be_bytes());
+        assert_eq!(image_dimensions(&png), Some((1200, 1800)));
+
+        assert_eq!(
+            image_dimensions(b"GIF89a\x20\x03\x58\x02"),
+            Some((800, 600))
+        );
+
+        let mut bmp = b"BM".to_vec();
+        bmp.resize(18, 0);
+        bmp.extend_from_slice(&640i32.to_le_bytes());
+        bmp.extend_from_slice(&(-480i32).to_le_bytes());
+        assert_eq!(image_dimensions(&bmp), Some((640, 480)));
+
+        let mut webp = b"RIFF\x00\x00\x00\x00WEBPVP8X\x0a\x00\x00\x00\x00\x00\x00\x00".to_vec();
+        webp.extend_from_slice(&[0xAF, 0x04, 0x00, 0x07, 0x07, 0x00]);
+        assert_eq!(image_dimensions(&webp), Some((1200, 1800)));
+
+        // an app0 segment before the frame header
+        let mut jpeg = b"\xFF\xD8\xFF\xE0\x00\x10JFIF\x00".to_vec();
+        jpeg.resize(2 + 2 + 16, 0);
+        jpeg.extend_from_slice(b"\xFF\xC0\x00\x11\x08");
+        jpeg.extend_from_slice(&1800u16.to_be_bytes());
+        jpeg.extend_from_slice(&1200u16.to_be_bytes());
+        assert_eq!(image_dimensions(&jpeg), Some((1200, 1800)));
+
+        assert_eq!(image_dimensions(b"\xFF\xD8\xFF\xE0\x00"), None);
+        assert_eq!(image_dimensions(b"not an image"), None);
+    }
+
+    #[test]
+    fn book_cost_counts_decoded_pages() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut png = b"\x89PNG\r\n\x1a\n\x00\x00\x00\x0dIHDR".to_vec();
+        png.extend_from_slice(&1000u32.to_be_bytes());
+        png.extend_from_slice(&2000u32.to_be_bytes());
+        std::fs::write(dir.path().join("001.png"), &png).unwrap();
+        // copied as is, only its bytes count
+        std::fs::write(dir.path().join("002.jpg"), b"\xFF\xD8\xFF").unwrap();
+        assert_eq!(
+            book_cost(dir.path()),
+            COPY_BUF_LEN as u64 + png.len() as u64 + 3 + 1000 * 2000 * 4
+        );
+    }
+
+    fn write_files(dir: &Path, files: &[&str]) {
+        for file in files {
+            let path = dir.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, file.as_bytes()).unwrap();
+        }
+    }
+
+    fn files_under(dir: &Path) -> Vec<String> {
+        let mut files: Vec<String> = WalkDir::new(dir)
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().is_file())
+            .map(|e| {
+                let path = e.path().strip_prefix(dir).unwrap();
+                path.to_string_lossy().replace('\\', "/")
+            })
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn flatten_collapses_nested_single_folders() {
+        let dir = tempfile::tempdir().unwrap();
+        // the trash file does not count as a second top-level entry
+        write_files(
+            dir.path(),
+            &["Title/Title/001.jpg", "Title/Title/002.jpg", "Thumbs.db"],
+        );
+        let moves = flatten_dir(dir.path(), false).unwrap();
+        assert_eq!(moves.len(), 2);
+        assert_eq!(files_under(dir.path()), ["001.jpg", "002.jpg", "Thumbs.db"]);
+        assert_eq!(
+            std::fs::read(dir.path().join("001.jpg")).unwrap(),
+            b"Title/Title/001.jpg"
+        );
+        assert!(!dir.path().join("Title").exists());
+    }
+
+    #[test]
+    fn flatten_stages_into_an_old_folder_name() {
+        let dir = tempfile::tempdir().unwrap();
+        // `A/A/001.jpg` becomes `A/001.jpg`, inside the folder it is lifted out of
+        write_files(dir.path(), &["A/A/001.jpg", "A/cover.jpg"]);
+        flatten_dir(dir.path(), false).unwrap();
+        assert_eq!(files_under(dir.path()), ["A/001.jpg", "cover.jpg"]);
+        assert_eq!(
+            std::fs::read(dir.path().join("A/001.jpg")).unwrap(),
+            b"A/A/001.jpg"
+        );
+        assert!(!dir.path().join(".flatten").exists());
+    }
+
+    #[test]
+    fn flatten_keeps_clashing_names() {
+        let dir = tempfile::tempdir().unwrap();
+        // `c01/001.jpg` is walked first and would become the `c01_001.jpg` already there
+        write_files(
+            dir.path(),
+            &["c01/001.jpg", "c01_001.jpg", "c02/001.jpg", "c02/002.jpg"],
+        );
+        let moves = flatten_dir(dir.path(), true).unwrap();
+        assert_eq!(moves.len(), 2);
+        assert_eq!(
+            files_under(dir.path()),
+            ["c01/001.jpg", "c01_001.jpg", "c02_001.jpg", "c02_002.jpg"]
+        );
+        assert_eq!(
+            std::fs::read(dir.path().join("c01_001.jpg")).unwrap(),
+            b"c01_001.jpg"
+        );
+    }
+
+    fn unicode_path_field(raw: &[u8], name: &str) -> Vec<u8> {
+        let mut field = UNICODE_PATH_ID.to_le_bytes().to_vec();
+        field.extend_from_slice(&(5 + name.len() as u16).to_le_bytes());
+        field.push(1);
+        field.extend_from_slice(&crc32fast::hash(raw).to_le_bytes());
+        field.extend_from_slice(name.as_bytes());
+        field
+    }
+
+    #[test]
+    fn unicode_path_checks_the_crc() {
+        let raw = b"caf\x82.jpg";
+        let field = unicode_path_field(raw, "café 2.jpg");
+        assert_eq!(unicode_path(raw, &field).as_deref(), Some("café 2.jpg"));
+        let encoding = NameEncoding::default();
+        let (name, source) = decode_zip_filename(raw, false, &field, &encoding).unwrap();
+        assert_eq!(
+            (name.as_str(), source),
+            ("café 2.jpg", NameSource::UnicodePath)
+        );
+
+        // renamed by a tool that kept the old field
+        let stale = unicode_path_field(b"old.jpg", "old.jpg");
+        assert_eq!(unicode_path(raw, &stale), None);
+        let (name, source) = decode_zip_filename(raw, false, &stale, &encoding).unwrap();
+        assert_eq!((name.as_str(), source), ("café.jpg", NameSource::Cp437));
+    }
+
+    #[test]
+    fn unicode_path_rejects_bad_fields() {
+        let raw = b"caf\x82.jpg";
+        let mut field = unicode_path_field(raw, "café.jpg");
+        field[4] = 2;
+        assert_eq!(unicode_path(raw, &field), None);
+        // too short for the crc
+        assert_eq!(
+            unicode_path(raw, &[0x75, 0x70, 0x03, 0x00, 0x01, 0x00, 0x00]),
+            None
+        );
+        let mut field = unicode_path_field(raw, "café.jpg");
+        field.truncate(field.len() - 2);
+        assert_eq!(unicode_path(raw, &field), None);
+    }
+
+    #[test]
+    fn find_extra_field_stops_at_truncated_fields() {
+        let mut extra = vec![0x55, 0x54, 0x05, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00];
+        extra.extend_from_slice(&[0x75, 0x70, 0x02, 0x00, 0xAA, 0xBB]);
+        assert_eq!(
+            find_extra_field(&extra, UNICODE_PATH_ID),
+            Some(&[0xAA, 0xBB][..])
+        );
+        assert_eq!(
+            find_extra_field(&extra, EXTENDED_TIMESTAMP_ID).map(<[u8]>::len),
+            Some(5)
+        );
+        assert_eq!(find_extra_field(&extra, 0x0001), None);
+        // a size running past the end, or a header cut short
+        assert_eq!(find_extra_field(&extra[..14], UNICODE_PATH_ID), None);
+        assert_eq!(find_extra_field(&extra[..11], UNICODE_PATH_ID), None);
+        assert_eq!(find_extra_field(&[], UNICODE_PATH_ID), None);
+    }
+
+    #[test]
+    fn dos_datetime_is_local_time() {
+        use chrono::TimeZone;
+
+        let local = chrono::Local
+            .with_ymd_and_hms(2021, 7, 4, 13, 30, 42)
+            .unwrap();
+        let mtime = SystemTime::from(local);
+        let dos = zip_datetime(mtime).unwrap();
+        assert_eq!(
+            (
+                dos.year(),
+                dos.month(),
+                dos.day(),
+                dos.hour(),
+                dos.minute(),
+                dos.second()
+            ),
+            (2021, 7, 4, 13, 30, 42)
+        );
+        assert_eq!(
+            dos_datetime_to_system_time(2021, 7, 4, 13, 30, 42),
+            Some(mtime)
+        );
+    }
+
+    fn candidates() -> Vec<String> {
+        LEGACY_ENCODINGS
+            .iter()
+            .map(|label| label.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn round_trip_needs_the_same_bytes_back() {
+        assert_eq!(
+            round_trip(b"\xca\xe9.jpg", "GBK").as_deref(),
+            Some("书.jpg")
+        );
+        assert_eq!(
+            round_trip(b"\x8f\xad.jpg", "Shift_JIS").as_deref(),
+            Some("少.jpg")
+        );
+        // a lead byte without its trail byte
+        assert_eq!(round_trip(b"\xca.jpg", "GBK"), None);
+        assert_eq!(round_trip(b"\xca\xe9.jpg", "no-such-encoding"), None);
+    }
+
+    #[test]
+    fn archive_encoding_picks_the_best_clean_candidate() {
+        let names: [&[u8]; 2] = [b"\xca\xe9.jpg", b"01\xb5\xda.jpg"];
+        let decision = archive_encoding(names.iter().map(|raw| (*raw, &[][..])), &candidates());
+        assert_eq!(decision.undecided, 2);
+        assert_eq!(decision.chosen.as_deref(), Some("GBK"));
+        let gbk = decision.scores.iter().find(|s| s.label == "GBK").unwrap();
+        assert_eq!((gbk.clean, gbk.script), (2, 1000));
+    }
+
+    #[test]
+    fn archive_encoding_never_chooses_a_lossy_candidate() {
+        // the second name is not gbk, it must not be decoded as such
+        let names: [&[u8]; 2] = [b"\xca\xe9.jpg", b"\xca.jpg"];
+        let decision = archive_encoding(
+            names.iter().map(|raw| (*raw, &[][..])),
+            &[String::from("GBK")],
+        );
+        assert_eq!(decision.undecided, 2);
+        assert_ne!(decision.chosen.as_deref(), Some("GBK"));
+        // a guess from chardet may still decode both
+        if let Some(label) = &decision.chosen {
+            assert!(names.iter().all(|raw| round_trip(raw, label).is_some()));
+        }
+    }
+
+    #[test]
+    fn archive_encoding_skips_decided_names() {
+        let field = unicode_path_field(b"\xca\xe9.jpg", "书.jpg");
+        let names: [(&[u8], &[u8]); 3] = [
+            ("书.jpg".as_bytes(), &[]),
+            (b"a.jpg", &[]),
+            (b"\xca\xe9.jpg", &field),
+        ];
+        let decision = archive_encoding(names, &candidates());
+        assert_eq!(decision.undecided, 0);
+        assert_eq!(decision.chosen, None);
+        assert!(decision.scores.is_empty());
+    }
+
+    #[test]
+    fn glob_match_single_segment() {
+        assert!(glob_match("*.zip", "a.zip"));
+        assert!(glob_match("*.zip", ".zip"));
+        assert!(!glob_match("*.zip", "jp/a.zip"));
+        assert!(!glob_match("*.zip", "a.zip.bak"));
+        assert!(glob_match("jp/?.zip", "jp/a.zip"));
+        assert!(!glob_match("jp/?.zip", "jp/ab.zip"));
+        assert!(!glob_match("jp?a.zip", "jp/a.zip"));
+        assert!(glob_match("第?巻.zip", "第1巻.zip"));
+        assert!(glob_match("a.zip", "a.zip"));
+        assert!(!glob_match("a.zip", "A.zip"));
+    }
+
+    #[test]
+    fn glob_match_across_segments() {
+        assert!(glob_match("**/jp/*.zip", "x/y/jp/a.zip"));
+        assert!(!glob_match("**/jp/*.zip", "x/jp/sub/a.zip"));
+        assert!(glob_match("jp/**", "jp/sub/a.zip"));
+        assert!(glob_match("**", "x/y/a.zip"));
+        assert!(glob_match("**.zip", "x/y/a.zip"));
+        assert!(glob_match("x/**/a.zip", "x/y/z/a.zip"));
+        assert!(!glob_match("x/**/a.zip", "y/x/a/a.zip"));
+        assert!(glob_match("", ""));
+        assert!(!glob_match("", "a.zip"));
+    }
+
+    #[test]
+    fn is_trash_dirs_and_ext() {
+        assert!(is_trash(Path::new("__MACOSX/c01/._001.jpg"), false));
+        assert!(is_trash(Path::new("c01/__MACOSX"), true));
+        assert!(is_trash(Path::new("c01/site.url"), false));
+        assert!(!is_trash(Path::new("c01.url"), true));
+        assert!(!is_trash(Path::new("c01/001.jpg"), false));
+    }
+
+    #[test]
+    fn is_archive_entry_sniffs_the_head() {
+        let ext = vec!["zip".to_string()];
+        assert!(is_archive_entry("c01/inner.zip", b"", &ext, false));
+        assert!(is_archive_entry(
+            "c01/inner.bin",
+            b"PK\x03\x04rest",
+            &ext,
+            true
+        ));
+        assert!(!is_archive_entry(
+            "c01/inner.bin",
+            b"PK\x03\x04rest",
+            &ext,
+            false
+        ));
+        assert!(!is_archive_entry(
+            "c01/book.epub",
+            b"PK\x03\x04rest",
+            &ext,
+            true
+        ));
+        assert!(!is_archive_entry(
+            "c01/001.jpg",
+            b"\xFF\xD8\xFF",
+            &ext,
+            true
+        ));
+    }
+
+    #[test]
+    fn is_archive_file_sniffs_a_wrong_extension() {
+        let dir = tempfile::tempdir().unwrap();
+        let ext = vec!["zip".to_string(), "cbz".to_string()];
+        let mut tar_head = vec![0u8; ARCHIVE_SNIFF_LEN];
+        tar_head[TAR_MAGIC_OFFSET..].copy_from_slice(&TAR_MAGIC);
+        for (name, content) in [
+            ("renamed.bin", b"PK\x03\x04rest".to_vec()),
+            ("renamed.rar", b"7z\xBC\xAF\x27\x1Crest".to_vec()),
+            ("renamed.dat", tar_head),
+            ("upper.CBZ", vec![]),
+            ("book.epub", b"PK\x03\x04rest".to_vec()),
+            ("notes.txt", b"PK".to_vec()),
+        ] {
+            std::fs::write(dir.path().join(name), content).unwrap();
+        }
+        let is_archive = |name: &str, sniff| is_archive_file(&dir.path().join(name), &ext, sniff);
+        assert!(is_archive("renamed.bin", true));
+        assert!(!is_archive("renamed.bin", false));
+        assert!(is_archive("renamed.rar", true));
+        assert!(is_archive("renamed.dat", true));
+        assert!(is_archive("upper.CBZ", false));
+        // a zip container that is no book, and a file too short for any signature
+        assert!(!is_archive("book.epub", true));
+        assert!(!is_archive("notes.txt", true));
+    }
+
+    #[test]
+    fn rezip_ext_per_output_format() {
+        assert_eq!(rezip_ext("zip", OutputFormat::Zip), None);
+        assert_eq!(rezip_ext("cbz", OutputFormat::Zip), None);
+        assert_eq!(rezip_ext("7z", OutputFormat::Zip).as_deref(), Some("zip"));
+        assert_eq!(rezip_ext("cb7", OutputFormat::Zip).as_deref(), Some("cbz"));
+        assert_eq!(
+            rezip_ext("tar.gz", OutputFormat::Zip).as_deref(),
+            Some("zip")
+        );
+        assert_eq!(rezip_ext("cbt", OutputFormat::Zip).as_deref(), Some("cbz"));
+        assert_eq!(rezip_ext("tar", OutputFormat::Tar), None);
+        assert_eq!(rezip_ext("cbz", OutputFormat::Tar).as_deref(), Some("cbt"));
+        assert_eq!(rezip_ext("tgz", OutputFormat::Tar).as_deref(), Some("tar"));
+    }
+
+    #[test]
+    fn resolve_output_root_per_prefix() {
+        let home = dirs_next::home_dir().unwrap();
+        let cwd = std::env::current_dir().unwrap();
+        assert_eq!(
+            resolve_output_root("/srv/out").unwrap(),
+            Path::new("/srv/out")
+        );
+        assert_eq!(resolve_output_root("./out").unwrap(), cwd.join("./out"));
+        assert_eq!(resolve_output_root("../out").unwrap(), cwd.join("../out"));
+        assert_eq!(resolve_output_root("~/out").unwrap(), home.join("out"));
+        // a bare relative path has always been under home, not the cwd
+        assert_eq!(
+            resolve_output_root("out/books").unwrap(),
+            home.join("out/books")
+        );
+    }
+
+    #[test]
+    fn mirror_out_dir_keeps_the_input_tree() {
+        let mirror = |origin| PathBuf::from(mirror_out_dir("/in", origin, "/out"));
+        assert_eq!(mirror("/in/vol1.zip"), Path::new("/out"));
+        assert_eq!(mirror("/in/A/vol1.zip"), Path::new("/out/A"));
+        assert_eq!(mirror("/in/A/B/C/vol1.zip"), Path::new("/out/A/B/C"));
+        // not under the input root, straight into the output
+        assert_eq!(mirror("/elsewhere/vol1.zip"), Path::new("/out"));
+    }
+
+    #[test]
+    fn partial_path_is_a_hidden_sibling() {
+        assert_eq!(
+            partial_path(Path::new("out/c01/vol1.cbz")),
+            Path::new("out/c01/.vol1.cbz.rezip-partial")
+        );
+    }
+
+    #[test]
+    fn commit_partial_replaces_the_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let dst = dir.path().join("vol1.cbz");
+        let partial = partial_path(&dst);
+        std::fs::write(&dst, "old").unwrap();
+        std::fs::write(&partial, "new").unwrap();
+        commit_partial(&partial, &dst).unwrap();
+        assert_eq!(std::fs::read_to_string(&dst).unwrap(), "new");
+        assert!(!partial.exists());
+    }
+
+    #[test]
+    fn clean_partials_leaves_recent_ones() {
+        let dir = tempfile::tempdir().unwrap();
+        let stale = partial_path(&dir.path().join("c01/vol1.cbz"));
+        let running = partial_path(&dir.path().join("vol2.cbz"));
+        write_files(dir.path(), &["c01/vol1.cbz", "vol2.cbz"]);
+        std::fs::write(&stale, "crashed").unwrap();
+        std::fs::write(&running, "writing").unwrap();
+        let hour_ago = SystemTime::now() - Duration::from_secs(3600);
+        filetime::set_file_mtime(&stale, filetime::FileTime::from_system_time(hour_ago)).unwrap();
+
+        let removed = clean_partials(dir.path(), Duration::from_secs(15 * 60));
+        assert_eq!(removed, vec![stale]);
+        assert!(running.exists());
+        assert_eq!(files_under(dir.path()).len(), 3);
+    }
+
+    #[test]
+    fn renumber_skips_macosx() {
+        let dir = tempfile::tempdir().unwrap();
+        write_files(
+            dir.path(),
+            &["a.jpg", "b.jpg", "__MACOSX/._a.jpg", "__MACOSX/._b.jpg"],
+        );
+        let renamed = renumber_pages(dir.path()).unwrap();
+        assert_eq!(renamed.len(), 2);
+        assert_eq!(
+            files_under(dir.path()),
+            ["001.jpg", "002.jpg", "__MACOSX/._a.jpg", "__MACOSX/._b.jpg"]
+        );
+    }
+}
@@ -0,0 +1,246 @@
+use encoding::{label::encoding_from_whatwg_label, DecoderTrap};
+
+use crate::MyError;
+use crate::constant::UNICODE_PATH_ID;
+use crate::helper::{self, ArchiveEncoding, NameEncoding, NameSource};
+
+/// How one entry name was decoded and what else it could have been.
+#[derive(Clone, Debug)]
+pub struct NameDiagnostic {
+    pub raw: Vec<u8>,
+    pub name: String,
+    pub source: NameSource,
+    pub utf8_flag: bool,
+    /// `None` without an Info-ZIP unicode path field, `Some(false)` if it is stale
+    pub unicode_path: Option<bool>,
+    /// chardet on this name alone
+    pub guess: String,
+    pub confidence: f32,
+    /// (encoding, decoded name, whether it round-trips to the raw bytes)
+    pub alternatives: Vec<(String, String, bool)>,
+}
+
+/// The name decoding of one zip, see `zip::inspect`.
+#[derive(Clone, Debug)]
+pub struct ArchiveDiagnostic {
+    pub path: String,
+    pub entries: usize,
+    pub forced: Option<String>,
+    pub encoding: ArchiveEncoding,
+    /// only the names that were not plain ascii
+    pub names: Vec<NameDiagnostic>,
+}
+
+// the same decisions `decode_zip_filename` makes during a run, plus what it left out.
+// names are (raw, utf-8 flag, extra field) in central directory order
+pub fn diagnose(
+    path: &str,
+    names: &[(Vec<u8>, bool, Vec<u8>)],
+    encoding: &NameEncoding,
+) -> Result<ArchiveDiagnostic, MyError> {
+    let mut encoding = encoding.clone();
+    let decision = match encoding.forced {
+        Some(_) => ArchiveEncoding::default(),
+        None => helper::archive_encoding(
+            names
+                .iter()
+                .map(|(raw, _, extra)| (raw.as_slice(), extra.as_slice())),
+            &encoding.candidates,
+        ),
+    };
+    encoding.archive = decision.chosen.clone();
+
+    let mut labels = encoding.candidates.clone();
+    if let Some(label) = &decision.chosen {
+        if !labels.iter().any(|l| l.eq_ignore_ascii_case(label)) {
+            labels.push(label.clone());
+        }
+    }
+
+    let mut diagnostics = vec![];
+    for (raw, utf8_flag, extra) in names {
+        let unicode_path = helper::find_extra_field(extra, UNICODE_PATH_ID)
+            .map(|_| helper::unicode_path(raw, extra).is_some());
+        if raw.is_ascii() && unicode_path.is_none() {
+            continue;
+        }
+        let (name, source) = helper::decode_zip_filename(raw, *utf8_flag, extra, &encoding)?;
+        let (guess, confidence, _) = chardet::detect(raw);
+        let alternatives = labels
+            .iter()
+            .filter_map(|label| {
+                let decoded = encoding_from_whatwg_label(label)?
+                    .decode(raw, DecoderTrap::Replace)
+                    .ok()?;
+                let clean = helper::round_trip(raw, label).is_some();
+                Some((label.clone(), decoded, clean))
+            })
+            .collect();
+        diagnostics.push(NameDiagnostic {
+            raw: raw.clone(),
+            name,
+            source,
+            utf8_flag: *utf8_flag,
+            unicode_path,
+            guess,
+            confidence,
+            alternatives,
+        });
+    }
+
+    Ok(ArchiveDiagnostic {
+        path: path.to_string(),
+        entries: names.len(),
+        forced: encoding.forced,
+        encoding: decision,
+        names: diagnostics,
+    })
+}
+
+impl ArchiveDiagnostic {
+    pub fn print(&self) {
+        println!(
+            "[inspect] {} {} entries, {} not ascii",
+            self.path,
+            self.entries,
+            self.names.len()
+        );
+        if let Some(label) = &self.forced {
+            println!("[inspect]   forced {label}");
+        } else if self.encoding.undecided > 0 {
+            println!(
+                "[inspect]   archive {} for {} name(s), chardet {} ({:.2})",
+                self.encoding.chosen.as_deref().unwrap_or("none"),
+                self.encoding.undecided,
+                self.encoding.guess,
+                self.encoding.confidence
+            );
+            for score in &self.encoding.scores {
+                println!(
+                    "[inspect]     {}: {} clean, {} ‰ script",
+                    score.label, score.clean, score.script
+                );
+            }
+        }
+        for name in &self.names {
+            println!("[inspect]   {} ({})", name.name, name.source);
+            let unicode_path = match name.unicode_path {
+                None => "none",
+                Some(true) => "valid",
+                Some(false) => "stale",
+            };
+            println!(
+                "[inspect]     raw {}, utf-8 flag {}, unicode path {unicode_path}, chardet {} ({:.2})",
+                hex(&name.raw),
+                name.utf8_flag,
+                name.guess,
+                name.confidence
+            );
+            for (label, decoded, clean) in &name.alternatives {
+                let mark = if *clean { "" } else { " (lossy)" };
+                println!("[inspect]     {label}: {decoded}{mark}");
+            }
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        let scores: Vec<String> = self
+            .encoding
+            .scores
+            .iter()
+            .map(|score| {
+                format!(
+                    "{{\"label\":{},\"clean\":{},\"script\":{}}}",
+                    json_str(&score.label),
+                    score.clean,
+                    score.script
+                )
+            })
+            .collect();
+        let names: Vec<String> = self
+            .names
+            .iter()
+            .map(|name| {
+                let alternatives: Vec<String> = name
+                    .alternatives
+                    .iter()
+                    .map(|(label, decoded, clean)| {
+                        format!(
+                            "{{\"encoding\":{},\"name\":{},\"clean\":{clean}}}",
+                            json_str(label),
+                            json_str(decoded)
+                        )
+                    })
+                    .collect();
+                format!(
+                    "{{\"raw\":\"{}\",\"name\":{},\"source\":{},\"utf8_flag\":{},\
+                     \"unicode_path\":{},\"guess\":{},\"confidence\":{},\"alternatives\":[{}]}}",
+                    hex(&name.raw),
+                    json_str(&name.name),
+                    json_str(&name.source.to_string()),
+                    name.utf8_flag,
+                    name.unicode_path
+                        .map_or(String::from("null"), |valid| valid.to_string()),
+                    json_str(&name.guess),
+                    name.confidence,
+                    alternatives.join(",")
+                )
+            })
+            .collect();
+        format!(
+            "{{\"path\":{},\"entries\":{},\"forced\":{},\"archive_encoding\":{},\
+             \"undecided\":{},\"guess\":{},\"confidence\":{},\"scores\":[{}],\"names\":[{}]}}",
+            json_str(&self.path),
+            self.entries,
+            self.forced
+                .as_deref()
+                .map_or(String::from("null"), json_str),
+            self.encoding
+                .chosen
+                .as_deref()
+                .map_or(String::from("null"), json_str),
+            self.encoding.undecided,
+            json_str(&self.encoding.guess),
+            self.encoding.confidence,
+            scores.join(","),
+            names.join(",")
+        )
+    }
+}
+
+fn hex(raw: &[u8]) -> String {
+    raw.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn json_str(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_str_escapes() {
+        assert_eq!(json_str(r#"say "hi""#), r#""say \"hi\"""#);
+        assert_eq!(json_str(r"c01\001.jpg"), r#""c01\\001.jpg""#);
+        assert_eq!(json_str("a\nb\r\tc"), r#""a\nb\r\tc""#);
+        assert_eq!(json_str("\u{0}\u{1b}\u{1f}"), r#""\u0000\u001b\u001f""#);
+        // anything past the control chars goes through as utf-8, DEL included
+        assert_eq!(json_str("第1話/ページ\u{7f}"), "\"第1話/ページ\u{7f}\"");
+        assert_eq!(json_str(""), r#""""#);
+    }
+}
@@ -1,5 +1,12 @@
+pub mod backup;
+pub mod budget;
+pub mod config;
 pub mod constant;
 pub mod helper;
+pub mod inspect;
 mod my_error;
+pub mod report;
+mod sevenz;
+pub mod tar;
 pub mod zip;
 pub use my_error::{CustomError, MyError};
@@ -1,19 +1,93 @@
 use chalk_rs::Chalk;
-use comic_rezip::constant::{TRANSFORM_EXT, TRASH_EXT};
-use comic_rezip::zip;
+use comic_rezip::budget::MemoryBudget;
+use comic_rezip::config::{
+    Collision, Command, Config, FlattenMode, MtimeSource, NestedMode, OutputFormat,
+};
+use comic_rezip::constant::{PARTIAL_MAX_AGE, STDIO_PATH, TRANSFORM_EXT};
+use comic_rezip::helper::{ArchiveKind, NameEncoding};
+use comic_rezip::report::{BookReport, RunReport};
+use comic_rezip::{backup, helper, tar, zip, CustomError, MyError};
 use image_convert::{to_jpg, ImageResource, JPGConfig};
 use std::env;
-use std::io::ErrorKind;
-use std::path::Path;
+use std::future::Future;
+use std::io::{ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use tokio::fs::{self};
 use walkdir::{DirEntry, WalkDir};
 
-async fn process_zip_file(full_path: String, out_path: String) {
+async fn process_zip_file(full_path: String, config: Arc<Config>, report: Arc<RunReport>) {
     println!("[async process_zip_file]({full_path}) entered");
 
+    let dest_file = match helper::get_out_zip_path(
+        &full_path,
+        &helper::mirror_out_dir(&config.input, &full_path, &config.output),
+        config.out_ext.as_deref(),
+        config.format,
+    ) {
+        Ok(dest_file) => dest_file,
+        Err(e) => {
+            eprintln!("{e}");
+            return;
+        }
+    };
+    let Some((dest_file, fingerprint)) =
+        claim_dest(dest_file, &config, Some(Path::new(&full_path)), &report)
+    else {
+        return;
+    };
+
+    // no backup, no replacing
+    let backup = if config.in_place {
+        match backup::backup_file(
+            Path::new(&full_path),
+            Path::new(&dest_file),
+            &config.backup_dir,
+        ) {
+            Ok(backup) => Some(backup),
+            Err(e) => {
+                eprintln!("backup {full_path} failed: {e}");
+                return;
+            }
+        }
+    } else {
+        None
+    };
+
+    if uses_pipeline(&full_path, &config) {
+        let time = std::time::Instant::now();
+        match transcode_book(&full_path, &dest_file, &config, fingerprint.clone()).await {
+            Ok(book) => {
+                println!(
+                    "[async process_zip_file]({full_path}) transcode {} cost {:.2} s",
+                    Chalk::new().bold().string(&full_path),
+                    time.elapsed().as_millis() as f64 / 1000.0
+                );
+                if let Some(backup) = &backup {
+                    let outputs = written_outputs(&dest_file, book.written);
+                    finish_in_place(&full_path, &outputs, backup, book.written);
+                }
+                report.add(book);
+                return;
+            }
+            // encrypted entries, nested archives... nothing has been written yet
+            Err(e) => println!("[pipeline] {full_path} falls back to extraction: {e}"),
+        }
+    }
+
+    let passwords =
+        match helper::load_passwords(Path::new(&full_path), config.password_file.as_deref()) {
+            Ok(passwords) => passwords,
+            Err(e) => {
+                eprintln!("load passwords failed: {e}");
+                vec![]
+            }
+        };
+
+    let encoding = config.name_encoding(Some(Path::new(&full_path)));
     let time = std::time::Instant::now();
-    match zip::unzip(full_path.clone(), out_path.clone()).await {
-        Ok((map, temp_path_str, dest_file)) => {
+    match zip::unzip(full_path.clone(), &passwords, &encoding).await {
+        Ok((map, names, temp_path_str)) => {
             println!(
                 "[async process_zip_file]({full_path}) unzip {} cost {:.2} s",
                 Chalk::new().bold().string(&full_path),
@@ -29,138 +103,778 @@ async fn process_zip_file(full_path: String, out_path: String) {
                 )
             }
 
-            // transform some files
-            let mut handles = vec![];
-            // [transform] *.png, *.bmp, *.JPG, *.webm, *.webp to standard JPEG
-            for entry in WalkDir::new(&temp_path_str)
-                .into_iter()
-                .filter_map(|e| e.ok())
-            {
-                let fd_path = entry.path().to_owned();
-                if *&fd_path.is_file()
-                    && (TRANSFORM_EXT.iter().any(|ext| -> bool {
-                        fd_path
-                            .extension()
-                            .unwrap_or_default()
-                            .to_string_lossy()
-                            .eq(ext)
-                    }))
-                {
-                    if let Some(parent_path) = fd_path.parent() {
-                        if let Some(file_base_name) = fd_path.file_stem() {
-                            let target_path = Path::join(
-                                parent_path,
-                                String::from(file_base_name.to_string_lossy() + ".jpg"),
-                            );
-                            handles.push(tokio::spawn(async move {
-                                let mut config = JPGConfig::new();
-                                config.quality = 86;
-                                let input = ImageResource::from_path(fd_path.to_path_buf());
-                                let mut output = ImageResource::from_path(&target_path);
-                                let time = std::time::Instant::now();
-                                match to_jpg(&mut output, &input, &config) {
-                                    Ok(_) => {
-                                        // remove origin pic
-                                        loop {
-                                            match fs::remove_file(fd_path.to_path_buf()).await {
-                                                Ok(_) => {
-                                                    println!("[async process_zip_file] [async thread task] convert {:?} to {:?} ({} ms)", fd_path, target_path,time.elapsed().as_millis());
-                                                    break;
-                                                },
-                                                Err(e) => match e.kind() {
-                                                    ErrorKind::NotFound => break,
-                                                    _ => {}
-                                                },
-                                            }
-                                        }
-                                    }
-                                    Err(err) => {
-                                        eprint!("to_jpg src:{:?} error: {}", fd_path, err)
-                                    }
-                                }
-                            }));
-                        } else {
-                            eprintln!("[transform] fd_path.file_stem error");
-                        }
-                    } else {
-                        eprintln!("[transform] fd_path.parent error");
+            // inner books written in place of the source, whatever happens to the rest of it
+            let mut inner_books = vec![];
+            if config.nested == NestedMode::Split && config.nested_depth > 0 {
+                let (all_written, written) = split_nested(
+                    &temp_path_str,
+                    &dest_file,
+                    &config,
+                    &passwords,
+                    &encoding,
+                    &report,
+                )
+                .await;
+                inner_books = written;
+                if !has_pages(&temp_path_str) {
+                    match fs::remove_dir_all(&temp_path_str).await {
+                        Ok(_) => println!("clean tmp dir ok"),
+                        Err(e) => eprintln!("{e}"),
                     }
+                    // the inner books are all that is left of it, the source stays unless
+                    // every one of them made it
+                    if let Some(backup) = &backup {
+                        finish_in_place(&full_path, &inner_books, backup, all_written);
+                    }
+                    return;
                 }
             }
 
-            for handle in handles {
-                match handle.await {
-                    Ok(_) => {}
-                    Err(e) => eprint!("{:?}", e),
+            // after a split only the inner books that failed are left, they stay as they were
+            if config.nested == NestedMode::Merge {
+                match zip::expand_nested(
+                    Path::new(&temp_path_str),
+                    config.nested_depth,
+                    &config.archive_ext,
+                    config.sniff,
+                    &passwords,
+                    &encoding,
+                )
+                .await
+                {
+                    Ok(nested_map) => {
+                        for (k, v) in &nested_map {
+                            println!("[nested] {k} -> {v}")
+                        }
+                    }
+                    Err(e) => eprintln!("{e}"),
                 }
             }
 
-            // rezip dir
-            match zip::zip_dir(
+            let mut book = rezip_book(
                 &temp_path_str,
                 &dest_file,
-                Some(Box::new(|fd_entry: &DirEntry| -> bool {
-                    let fd_path = fd_entry.path();
-                    // [remove dir] __MACOSX, __MACOSX/*
-                    if fd_path.is_dir() && fd_path.to_string_lossy().contains("__MACOSX") {
-                        return false;
-                    }
-                    // [remove] *.url, *.db, *.txt
-                    if fd_path.is_file()
-                        && (TRASH_EXT.iter().any(|ext| -> bool {
-                            fd_path
-                                .extension()
-                                .unwrap_or_default()
-                                .to_string_lossy()
-                                .eq(ext)
-                        }))
-                    {
-                        return false;
-                    }
-                    return true;
-                })),
+                &config,
+                Some(Path::new(&full_path)),
+                fingerprint,
+            )
+            .await;
+            book.names = names;
+            if let Some(backup) = &backup {
+                inner_books.extend(written_outputs(&dest_file, book.written));
+                finish_in_place(&full_path, &inner_books, backup, book.written);
+            }
+            report.add(book);
+        }
+        Err(e) => {
+            eprintln!(
+                "unzip {} failed: {e}",
+                Chalk::new().bold().string(&full_path)
+            );
+            if let Some(backup) = &backup {
+                finish_in_place(&full_path, &[], backup, false);
+            }
+        }
+    }
+}
+
+// renumbering and flattening need the whole tree on disk
+fn uses_pipeline(full_path: &str, config: &Config) -> bool {
+    // raw copies keep the source encryption, or the lack of it
+    config.pipeline
+        && config.encrypt.is_none()
+        && config.format == OutputFormat::Zip
+        && !config.renumber
+        && config.flatten == FlattenMode::Off
+        && helper::archive_kind(Path::new(full_path)) == ArchiveKind::Zip
+}
+
+async fn transcode_book(
+    full_path: &str,
+    dest_file: &str,
+    config: &Config,
+    fingerprint: Option<String>,
+) -> Result<BookReport, MyError> {
+    // the same archives `expand_nested` would open
+    let needs_extraction = |name: &str, head: &[u8]| {
+        config.nested_depth > 0
+            && helper::is_archive_entry(name, head, &config.archive_ext, config.sniff)
+    };
+    let keep = |name: &str| !helper::is_trash(Path::new(name), name.ends_with('/'));
+    let transform_name = |name: &str| {
+        let path = Path::new(name);
+        let ext = path.extension()?.to_string_lossy();
+        TRANSFORM_EXT
+            .iter()
+            .any(|e| ext.eq(e))
+            .then(|| path.with_extension("jpg").to_string_lossy().into_owned())
+    };
+    let pipeline = zip::Pipeline {
+        needs_extraction: &needs_extraction,
+        keep: &keep,
+        transform_name: &transform_name,
+        transform: &convert_page,
+    };
+
+    let (renamed, names) = zip::transcode(
+        full_path,
+        dest_file,
+        &pipeline,
+        &zip_options(config, Some(Path::new(full_path)), fingerprint),
+        &config.name_encoding(Some(Path::new(full_path))),
+    )
+    .await?;
+    Ok(BookReport {
+        source: full_path.to_string(),
+        dest: dest_file.to_string(),
+        written: true,
+        renamed,
+        names,
+    })
+}
+
+// [transform] one page to a standard JPEG
+fn convert_page(from: &Path, to: &Path) -> Result<(), MyError> {
+    let mut config = JPGConfig::new();
+    config.quality = 86;
+    // a page we may not read is handed over as bytes, see helper::open_extracted
+    let input = match std::fs::File::open(from) {
+        Err(e) if e.kind() == ErrorKind::PermissionDenied => {
+            let mut data = vec![];
+            helper::open_extracted(from)?.read_to_end(&mut data)?;
+            ImageResource::Data(data)
+        }
+        _ => ImageResource::from_path(from.to_path_buf()),
+    };
+    let mut output = ImageResource::from_path(to);
+    to_jpg(&mut output, &input, &config).map_err(|e| {
+        MyError::from(CustomError::new(&format!(
+            "to_jpg src:{:?} error: {e}",
+            from
+        )))
+    })
+}
+
+fn written_outputs(dest_file: &str, written: bool) -> Vec<String> {
+    match written {
+        true => vec![dest_file.to_string()],
+        false => vec![],
+    }
+}
+
+// `outputs` are the books written in place of the source (its own book, the inner books of
+// a split), a restore removes them again. Once `replaced` the source only lives in the
+// backup, unless its book took its very path
+fn finish_in_place(source: &str, outputs: &[String], backup: &backup::Backup, replaced: bool) {
+    if outputs.is_empty() {
+        if let Err(e) = backup::discard(backup) {
+            eprintln!("discard backup {:?} failed: {e}", backup.file);
+        }
+        return;
+    }
+    if let Err(e) = backup::set_outputs(backup, outputs) {
+        eprintln!("record outputs in {:?} failed: {e}", backup.info);
+    }
+    if replaced
+        && !outputs
+            .iter()
+            .any(|output| helper::same_file(Path::new(source), Path::new(output)))
+    {
+        match std::fs::remove_file(source) {
+            Ok(_) => println!(
+                "[in-place] {source} -> {}, backup {:?}",
+                outputs.join(", "),
+                backup.file
+            ),
+            Err(e) => eprintln!("remove {source} failed: {e}"),
+        }
+    }
+}
+
+// `comic-rezip - out.cbz` or `comic-rezip - -`, output is a file path here, not a dir
+async fn process_stream(
+    config: Arc<Config>,
+    stdout: Option<std::fs::File>,
+    report: Arc<RunReport>,
+) {
+    println!("[async process_stream] entered");
+
+    let encoding = config.name_encoding(None);
+    let time = std::time::Instant::now();
+    match zip::unzip_stream(tokio::io::stdin(), &encoding).await {
+        Ok((map, names, temp_path_str)) => {
+            println!(
+                "[async process_stream] unzip stdin cost {:.2} s",
+                time.elapsed().as_millis() as f64 / 1000.0
+            );
+
+            for (k, v) in &map {
+                println!(
+                    "{k} -> {} -> {v}",
+                    mime_guess::from_ext(k.as_str())
+                        .first_or_octet_stream()
+                        .to_string()
+                )
+            }
+
+            if let Err(e) = zip::expand_nested(
+                Path::new(&temp_path_str),
+                config.nested_depth,
+                &config.archive_ext,
+                config.sniff,
+                &[],
+                &encoding,
             )
             .await
             {
-                Ok(_) => {}
-                Err(e) => eprintln!("{e}"),
+                eprintln!("{e}");
+            }
+
+            match stdout {
+                None => match claim_dest(config.output.clone(), &config, None, &report) {
+                    Some((dest_file, _)) => {
+                        let mut book =
+                            rezip_book(&temp_path_str, &dest_file, &config, None, None).await;
+                        book.names = names;
+                        report.add(book);
+                    }
+                    None => {
+                        if let Err(e) = fs::remove_dir_all(&temp_path_str).await {
+                            eprintln!("{e}");
+                        }
+                    }
+                },
+                Some(mut stdout) => {
+                    match rezip_to_stdout(&temp_path_str, &config, &mut stdout).await {
+                        Ok(mut book) => {
+                            book.names = names;
+                            report.add(book);
+                        }
+                        Err(e) => eprintln!("write stdout failed: {e}"),
+                    }
+                }
             }
+        }
+        Err(e) => eprintln!("unzip stdin failed: {e}"),
+    }
+}
+
+// the archive writers need to seek, so the book is built in a temp file first
+async fn rezip_to_stdout(
+    temp_path_str: &str,
+    config: &Config,
+    stdout: &mut std::fs::File,
+) -> Result<BookReport, MyError> {
+    let out_dir = tempfile::tempdir()?;
+    let dest_file = out_dir.path().join(match config.format {
+        OutputFormat::Zip => "stdout.zip",
+        OutputFormat::Tar => "stdout.tar",
+    });
+    let mut book_report = rezip_book(
+        temp_path_str,
+        &dest_file.to_string_lossy(),
+        config,
+        None,
+        None,
+    )
+    .await;
+    book_report.dest = String::from(STDIO_PATH);
+
+    let mut book = std::fs::File::open(&dest_file)?;
+    std::io::copy(&mut book, stdout)?;
+    stdout.flush()?;
+    Ok(book_report)
+}
 
-            // clean temp dir
-            match fs::remove_dir_all(&temp_path_str).await {
-                Ok(_) => println!("clean tmp dir ok"),
-                Err(e) => eprintln!("{e}"),
+// keep the real stdout for the archive bytes, everything printed goes to stderr from now on
+#[cfg(unix)]
+fn take_stdout() -> Result<std::fs::File, MyError> {
+    use std::os::fd::FromRawFd;
+
+    // SAFETY: plain fd juggling, the duplicated fd is owned by the returned File only
+    unsafe {
+        let fd = libc::dup(libc::STDOUT_FILENO);
+        if fd < 0 || libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO) < 0 {
+            return Err(MyError::Io(std::io::Error::last_os_error()));
+        }
+        Ok(std::fs::File::from_raw_fd(fd))
+    }
+}
+
+#[cfg(not(unix))]
+fn take_stdout() -> Result<std::fs::File, MyError> {
+    Err(MyError::from(comic_rezip::CustomError::new(
+        "writing the archive to stdout is only supported on unix",
+    )))
+}
+
+// every archive inside the book becomes a book of its own, written into a folder named
+// after the outer one. Returns whether every inner book was written, and the written ones
+async fn split_nested(
+    temp_path_str: &str,
+    dest_file: &str,
+    config: &Config,
+    passwords: &[String],
+    encoding: &NameEncoding,
+    report: &RunReport,
+) -> (bool, Vec<String>) {
+    let mut all_written = true;
+    let mut written_books = vec![];
+    let dest_dir = Path::new(dest_file)
+        .with_extension("")
+        .to_string_lossy()
+        .into_owned();
+
+    for inner_path in
+        zip::find_archives(Path::new(temp_path_str), &config.archive_ext, config.sniff)
+    {
+        let inner_path_str = inner_path.to_string_lossy().into_owned();
+        let inner_dest_file = match helper::get_out_zip_path(
+            &inner_path_str,
+            &dest_dir,
+            config.out_ext.as_deref(),
+            config.format,
+        ) {
+            Ok(inner_dest_file) => inner_dest_file,
+            Err(e) => {
+                eprintln!("{e}");
+                all_written = false;
+                continue;
+            }
+        };
+        let Some((inner_dest_file, fingerprint)) =
+            claim_dest(inner_dest_file, config, Some(&inner_path), report)
+        else {
+            // its book is already there, it must not end up in the outer one either
+            if let Err(e) = fs::remove_file(&inner_path).await {
+                eprintln!("{e}");
+                all_written = false;
+            }
+            continue;
+        };
+
+        match zip::unzip(inner_path_str.clone(), passwords, encoding).await {
+            Ok((_, names, inner_temp_path_str)) => {
+                if let Err(e) = zip::expand_nested(
+                    Path::new(&inner_temp_path_str),
+                    config.nested_depth - 1,
+                    &config.archive_ext,
+                    config.sniff,
+                    passwords,
+                    encoding,
+                )
+                .await
+                {
+                    eprintln!("{e}");
+                }
+                let mut book = rezip_book(
+                    &inner_temp_path_str,
+                    &inner_dest_file,
+                    config,
+                    Some(&inner_path),
+                    fingerprint,
+                )
+                .await;
+                book.names = names;
+                let written = book.written;
+                all_written &= written;
+                if written {
+                    written_books.push(inner_dest_file);
+                }
+                report.add(book);
+                // a failed inner book stays in the outer one as it was
+                if !written {
+                    eprintln!(
+                        "rezip {} failed, kept in the outer book",
+                        Chalk::new().bold().string(&inner_path_str)
+                    );
+                } else if let Err(e) = fs::remove_file(&inner_path).await {
+                    eprintln!("{e}");
+                }
+            }
+            Err(e) => {
+                eprintln!(
+                    "unzip {} failed: {e}",
+                    Chalk::new().bold().string(&inner_path_str)
+                );
+                all_written = false;
             }
         }
+    }
+    (all_written, written_books)
+}
+
+// anything left worth a book after the trash filter
+fn has_pages(temp_path_str: &str) -> bool {
+    WalkDir::new(temp_path_str)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .any(|e| e.file_type().is_file() && trash_filter(&e))
+}
+
+async fn process_image_dir(dir_path: String, config: Arc<Config>, report: Arc<RunReport>) {
+    println!("[async process_image_dir]({dir_path}) entered");
+
+    let dest_file = match helper::get_out_pack_path(
+        &dir_path,
+        &helper::mirror_out_dir(&config.input, &dir_path, &config.output),
+        config.out_ext.as_deref(),
+        config.format,
+    ) {
+        Ok(dest_file) => dest_file,
+        Err(e) => {
+            eprintln!("{e}");
+            return;
+        }
+    };
+    let Some((dest_file, fingerprint)) =
+        claim_dest(dest_file, &config, Some(Path::new(&dir_path)), &report)
+    else {
+        return;
+    };
+
+    match stage_dir(&dir_path).await {
+        Ok(temp_path_str) => report.add(
+            rezip_book(
+                &temp_path_str,
+                &dest_file,
+                &config,
+                Some(Path::new(&dir_path)),
+                fingerprint,
+            )
+            .await,
+        ),
         Err(e) => {
             eprintln!(
-                "unzip {} failed: {e}",
-                Chalk::new().bold().string(&full_path)
+                "stage {} failed: {e}",
+                Chalk::new().bold().string(&dir_path)
             )
         }
     }
 }
 
-// scan_dir eat all errors
-// let it panic
-fn scan_dir<'a>(path: &'a str, out_path: &'a str) {
+// copy the pages into a temp dir, the transform step deletes its sources. Dotfiles
+// (`.passwords`, `.DS_Store`, partial outputs) are no pages
+async fn stage_dir(dir_path: &str) -> Result<String, MyError> {
+    let tmp_dir = tempfile::tempdir()?;
+    println!("make tmp_dir {:?}", tmp_dir.path());
+
+    let mut entries = fs::read_dir(dir_path).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        if entry.file_type().await?.is_file()
+            && !entry.file_name().to_string_lossy().starts_with('.')
+        {
+            fs::copy(entry.path(), tmp_dir.path().join(entry.file_name())).await?;
+        }
+    }
+
+    Ok(tmp_dir
+        .into_path()
+        .as_os_str()
+        .to_string_lossy()
+        .into_owned())
+}
+
+// transform, filter and rezip an extracted (or staged) book, then clean its temp dir
+async fn rezip_book(
+    temp_path_str: &str,
+    dest_file: &str,
+    config: &Config,
+    source: Option<&Path>,
+    fingerprint: Option<String>,
+) -> BookReport {
+    let temp_path = Path::new(temp_path_str);
+    let mut renamed: Vec<(PathBuf, PathBuf)> = transform_dir(temp_path_str)
+        .await
+        .into_iter()
+        .map(|(from, to)| {
+            (
+                from.strip_prefix(temp_path).unwrap_or(&from).to_path_buf(),
+                to.strip_prefix(temp_path).unwrap_or(&to).to_path_buf(),
+            )
+        })
+        .collect();
+
+    // pages are numbered per folder before chapter folders become prefixes
+    if config.renumber {
+        match helper::renumber_pages(temp_path) {
+            Ok(renumbered) => chain_renames(&mut renamed, renumbered),
+            Err(e) => eprintln!("renumber pages failed: {e}"),
+        }
+    }
+    if config.flatten != FlattenMode::Off {
+        match helper::flatten_dir(temp_path, config.flatten == FlattenMode::Chapters) {
+            Ok(flattened) => chain_renames(&mut renamed, flattened),
+            Err(e) => eprintln!("flatten failed: {e}"),
+        }
+    }
+
+    // rezip dir
+    let rezip = match config.format {
+        OutputFormat::Zip => {
+            zip::zip_dir(
+                temp_path_str,
+                dest_file,
+                Some(Box::new(trash_filter)),
+                zip_options(config, source, fingerprint),
+            )
+            .await
+        }
+        OutputFormat::Tar => {
+            tar::tar_dir(
+                temp_path_str,
+                dest_file,
+                Some(Box::new(trash_filter)),
+                config.deterministic,
+                source_mtime(config, source),
+                fingerprint.as_deref(),
+            )
+            .await
+        }
+    };
+    let written = match rezip {
+        Ok(_) => true,
+        Err(e) => {
+            eprintln!("{e}");
+            false
+        }
+    };
+
+    // clean temp dir
+    match fs::remove_dir_all(temp_path_str).await {
+        Ok(_) => println!("clean tmp dir ok"),
+        Err(e) => eprintln!("{e}"),
+    }
+
+    BookReport {
+        source: source.map_or(String::from(STDIO_PATH), |source| {
+            source.to_string_lossy().into_owned()
+        }),
+        dest: dest_file.to_string(),
+        written,
+        renamed,
+        ..Default::default()
+    }
+}
+
+// a page renamed twice is reported once, under its original name
+fn chain_renames(renamed: &mut Vec<(PathBuf, PathBuf)>, step: Vec<(PathBuf, PathBuf)>) {
+    for (from, to) in step {
+        match renamed.iter_mut().find(|(_, current)| current == &from) {
+            Some((_, current)) => *current = to,
+            None => renamed.push((from, to)),
+        }
+    }
+}
+
+// apply the collision policy before any work, `None` skips the book. Books of one run
+// run at once and two sources may map to the same output (`a.7z` and `a.zip`), so the
+// output is claimed for the run as well
+fn claim_dest(
+    dest_file: String,
+    config: &Config,
+    source: Option<&Path>,
+    report: &RunReport,
+) -> Option<(String, Option<String>)> {
+    // in place, the source itself is always replaced
+    let exists = Path::new(&dest_file).exists()
+        && !(config.in_place
+            && source.is_some_and(|source| helper::same_file(source, Path::new(&dest_file))));
+    if exists && config.on_exist == Collision::Skip {
+        println!("[skip] {dest_file} already exists");
+        return None;
+    }
+
+    // always written into the book, so a later compare run can rely on it
+    let fingerprint =
+        source.and_then(
+            |source| match helper::fingerprint(source, &config.output_settings()) {
+                Ok(fingerprint) => Some(fingerprint),
+                Err(e) => {
+                    eprintln!("fingerprint {:?} failed: {e}", source);
+                    None
+                }
+            },
+        );
+    // not written yet, so there is nothing to overwrite or compare against
+    if !report.claim(Path::new(&dest_file)) {
+        if config.on_exist == Collision::Skip {
+            println!("[skip] {dest_file} is taken by another book");
+            return None;
+        }
+        let dest_file = helper::numbered_path(&dest_file, |path| report.claim(path));
+        return Some((dest_file, fingerprint));
+    }
+    if !exists {
+        return Some((dest_file, fingerprint));
+    }
+
+    match config.on_exist {
+        Collision::Skip => None,
+        Collision::Overwrite => Some((dest_file, fingerprint)),
+        Collision::Rename => Some((
+            helper::numbered_path(&dest_file, |path| report.claim(path)),
+            fingerprint,
+        )),
+        Collision::Compare => {
+            if fingerprint.is_some()
+                && helper::read_fingerprint(Path::new(&dest_file)) == fingerprint
+            {
+                println!("[skip] {dest_file} is up to date");
+                None
+            } else {
+                Some((dest_file, fingerprint))
+            }
+        }
+    }
+}
+
+// `None` leaves deterministic books at the fixed mtime of their format
+fn source_mtime(config: &Config, source: Option<&Path>) -> Option<std::time::SystemTime> {
+    match config.mtime {
+        MtimeSource::Fixed => None,
+        MtimeSource::Source => source
+            .and_then(|source| std::fs::metadata(source).ok())
+            .and_then(|metadata| metadata.modified().ok()),
+    }
+}
+
+fn zip_options(
+    config: &Config,
+    source: Option<&Path>,
+    fingerprint: Option<String>,
+) -> zip::ZipOptions {
+    let deterministic = if config.deterministic {
+        let source_mtime = source_mtime(config, source).and_then(helper::zip_datetime);
+        Some(source_mtime.unwrap_or_default())
+    } else {
+        None
+    };
+
+    zip::ZipOptions {
+        method: config.compress,
+        level: config.level,
+        deterministic,
+        fingerprint,
+        writer: config.zip_writer,
+        encrypt: config.encrypt.clone(),
+    }
+}
+
+// returns the (source, converted) path of every page turned into a jpeg
+async fn transform_dir(temp_path_str: &str) -> Vec<(PathBuf, PathBuf)> {
+    // transform some files
     let mut handles = vec![];
-    for (path, file_type) in WalkDir::new(path)
+    // [transform] *.png, *.bmp, *.JPG, *.webm, *.webp to standard JPEG
+    for entry in WalkDir::new(temp_path_str)
         .into_iter()
         .filter_map(|e| e.ok())
-        .map(|e| (Path::new(path).join(e.file_name()), e.file_type()))
     {
+        let fd_path = entry.path().to_owned();
+        if *&fd_path.is_file()
+            && (TRANSFORM_EXT.iter().any(|ext| -> bool {
+                fd_path
+                    .extension()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .eq(ext)
+            }))
+        {
+            if let Some(parent_path) = fd_path.parent() {
+                if let Some(file_base_name) = fd_path.file_stem() {
+                    let target_path = Path::join(
+                        parent_path,
+                        String::from(file_base_name.to_string_lossy() + ".jpg"),
+                    );
+                    handles.push(tokio::spawn(async move {
+                        let time = std::time::Instant::now();
+                        // the converted page keeps the timestamp and mode of its source
+                        let metadata = std::fs::metadata(&fd_path).ok();
+                        let mtime = metadata.as_ref().and_then(|m| m.modified().ok());
+                        #[cfg(unix)]
+                        let mode = metadata.as_ref().map(|m| {
+                            use std::os::unix::fs::PermissionsExt;
+                            m.permissions().mode() & 0o777
+                        });
+                        #[cfg(not(unix))]
+                        let mode = None;
+                        match convert_page(&fd_path, &target_path) {
+                            Ok(_) => {
+                                if let Err(e) = helper::restore_file_attrs(&target_path, mtime, mode) {
+                                    eprint!("restore attrs of {:?} error: {}", target_path, e)
+                                }
+                                // remove origin pic
+                                loop {
+                                    match fs::remove_file(fd_path.to_path_buf()).await {
+                                        Ok(_) => {
+                                            println!("[async process_zip_file] [async thread task] convert {:?} to {:?} ({} ms)", fd_path, target_path,time.elapsed().as_millis());
+                                            break;
+                                        },
+                                        Err(e) => match e.kind() {
+                                            ErrorKind::NotFound => break,
+                                            _ => {}
+                                        },
+                                    }
+                                }
+                                Some((fd_path, target_path))
+                            }
+                            Err(err) => {
+                                eprint!("{err}");
+                                None
+                            }
+                        }
+                    }));
+                } else {
+                    eprintln!("[transform] fd_path.file_stem error");
+                }
+            } else {
+                eprintln!("[transform] fd_path.parent error");
+            }
+        }
+    }
+
+    let mut converted = vec![];
+    for handle in handles {
+        match handle.await {
+            Ok(Some(pair)) => converted.push(pair),
+            Ok(None) => {}
+            Err(e) => eprint!("{:?}", e),
+        }
+    }
+    converted
+}
+
+fn trash_filter(fd_entry: &DirEntry) -> bool {
+    !helper::is_trash(fd_entry.path(), fd_entry.file_type().is_dir())
+}
+
+// scan_dir eat all errors
+// let it panic
+fn scan_dir(config: Arc<Config>, report: Arc<RunReport>, budget: Arc<MemoryBudget>) {
+    if config.pack {
+        return scan_image_dirs(config, report, budget);
+    }
+
+    // walked up front, in place the books land in the tree being scanned
+    let entries: Vec<_> = WalkDir::new(&config.input)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .map(|e| (e.path().to_path_buf(), e.file_type()))
+        .collect();
+
+    let mut handles = vec![];
+    for (path, file_type) in entries {
         if let Some(full_path) = path.to_str() {
             let full_path = full_path.to_string();
-            let out_path = out_path.to_string();
-
-            if file_type.is_file() && full_path.ends_with(".zip") {
-                handles.push(std::thread::spawn(|| {
-                    if let Ok(rt) = tokio::runtime::Runtime::new() {
-                        let local_set = tokio::task::LocalSet::new();
-                        local_set.spawn_local(process_zip_file(full_path, out_path));
-                        rt.handle().block_on(async { local_set.await });
-                    }
+            let config = Arc::clone(&config);
+            let report = Arc::clone(&report);
+
+            if file_type.is_file()
+                && helper::is_archive_file(&path, &config.archive_ext, config.sniff)
+            {
+                let cost = book_cost(&config, &path);
+                handles.push(spawn_book(&budget, cost, || {
+                    process_zip_file(full_path, config, report)
                 }));
             }
         } else {
@@ -177,21 +891,272 @@ fn scan_dir<'a>(path: &'a str, out_path: &'a str) {
     }
 }
 
+// every leaf folder (files only, no sub folders) is one book
+fn scan_image_dirs(config: Arc<Config>, report: Arc<RunReport>, budget: Arc<MemoryBudget>) {
+    let mut handles = vec![];
+    for entry in WalkDir::new(&config.input)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_dir() && is_leaf_dir(e.path()))
+    {
+        if let Some(dir_path) = entry.path().to_str() {
+            let dir_path = dir_path.to_string();
+            let config = Arc::clone(&config);
+            let report = Arc::clone(&report);
+            let cost = book_cost(&config, entry.path());
+            handles.push(spawn_book(&budget, cost, || {
+                process_image_dir(dir_path, config, report)
+            }));
+        } else {
+            eprintln!(
+                "{}",
+                Chalk::new()
+                    .light_red()
+                    .string(&format!("path {:?} to string failed", entry.path()))
+            )
+        }
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+}
+
+// no sub folders and at least one page, notes or subtitles alone are not a book
+fn is_leaf_dir(path: &Path) -> bool {
+    let mut has_page = false;
+    match std::fs::read_dir(path) {
+        Ok(entries) => {
+            for entry in entries.filter_map(|e| e.ok()) {
+                match entry.file_type() {
+                    Ok(t) if t.is_dir() => return false,
+                    Ok(t) if t.is_file() && helper::is_page(&entry.path()) => has_page = true,
+                    _ => {}
+                }
+            }
+            has_page
+        }
+        Err(_) => false,
+    }
+}
+
+// without a budget nothing waits on the cost, which is not worth opening every book for
+fn book_cost(config: &Config, path: &Path) -> u64 {
+    match config.memory_budget {
+        Some(_) => helper::book_cost(path),
+        None => 0,
+    }
+}
+
+// one thread with its own runtime per book, started once the budget has room for it
+fn spawn_book<F, Fut>(
+    budget: &Arc<MemoryBudget>,
+    cost: u64,
+    make_task: F,
+) -> std::thread::JoinHandle<()>
+where
+    F: FnOnce() -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + 'static,
+{
+    let budget = Arc::clone(budget);
+    std::thread::spawn(move || {
+        let _reservation = budget.reserve(cost);
+        if let Ok(rt) = tokio::runtime::Runtime::new() {
+            let local_set = tokio::task::LocalSet::new();
+            local_set.spawn_local(make_task());
+            rt.handle().block_on(async { local_set.await });
+        }
+    })
+}
+
+// zips only, the other formats have no name encoding to get wrong
+fn inspect(config: &Config) {
+    let mut paths: Vec<PathBuf> =
+        zip::find_archives(Path::new(&config.input), &config.archive_ext, config.sniff)
+            .into_iter()
+            .filter(|path| helper::archive_kind(path) == ArchiveKind::Zip)
+            .collect();
+    paths.sort();
+    let mut json = vec![];
+    for path in &paths {
+        match zip::inspect(path, &config.name_encoding(Some(path))) {
+            Ok(diagnostic) if config.json => json.push(diagnostic.to_json()),
+            Ok(diagnostic) => diagnostic.print(),
+            Err(e) => eprintln!("inspect {:?} failed: {e}", path),
+        }
+    }
+    if config.json {
+        println!("[{}]", json.join(","));
+    }
+}
+
 fn main() {
     let time = std::time::Instant::now();
     let args: Vec<String> = env::args().collect();
     // println!("{args:?}");
-    let _ = scan_dir(&args[1], &args[2]);
-    let output_path = Path::new(&args[2]);
-    if !output_path.exists() {
-        match std::fs::create_dir_all(output_path) {
-            Ok(_) => println!("auto create output dir {:}", &args[2]),
-            Err(e) => eprint!("{:?}", e),
+    let config = match Config::from_args(&args) {
+        Ok(config) => Arc::new(config),
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(2);
+        }
+    };
+
+    let report = Arc::new(RunReport::default());
+    let budget = Arc::new(MemoryBudget::new(config.memory_budget));
+
+    if config.command == Command::Restore {
+        match backup::restore(&config.backup_dir, &config.input) {
+            Ok(restored) => {
+                for (from, to) in &restored {
+                    println!("[restore] {:?} -> {:?}", from, to);
+                }
+                println!("[restore] {} file(s)", restored.len());
+            }
+            Err(e) => {
+                eprintln!("restore failed: {e}");
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if config.command == Command::Inspect {
+        inspect(&config);
+        return;
+    }
+
+    if config.input == STDIO_PATH {
+        let stdout = if config.output == STDIO_PATH {
+            match take_stdout() {
+                Ok(stdout) => Some(stdout),
+                Err(e) => {
+                    eprintln!("{e}");
+                    std::process::exit(2);
+                }
+            }
+        } else {
+            None
+        };
+        let book_report = Arc::clone(&report);
+        let _ = spawn_book(&budget, 0, || process_stream(config, stdout, book_report)).join();
+        report.print();
+        eprintln!(
+            "{:.2} sec main fn",
+            time.elapsed().as_millis() as f64 / 1000.0
+        );
+        return;
+    }
+
+    if let Ok(output_path) = helper::resolve_output_root(&config.output) {
+        for partial in helper::clean_partials(&output_path, PARTIAL_MAX_AGE) {
+            println!("remove partial output of a crashed run {:?}", partial);
         }
     }
 
+    let _ = scan_dir(Arc::clone(&config), Arc::clone(&report), budget);
+    match helper::resolve_output_root(&config.output) {
+        Ok(output_path) if !output_path.exists() => match std::fs::create_dir_all(&output_path) {
+            Ok(_) => println!("auto create output dir {:?}", output_path),
+            Err(e) => eprint!("{:?}", e),
+        },
+        Ok(_) => {}
+        Err(e) => eprint!("{:?}", e),
+    }
+
+    report.print();
     println!(
         "{:.2} sec main fn",
         time.elapsed().as_millis() as f64 / 1000.0
     );
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use comic_rezip::constant::FINGERPRINT_PREFIX;
+
+    fn config(on_exist: &str) -> Config {
+        let args: Vec<String> = ["comic-rezip", "in", "out", "--on-exist", on_exist]
+            .iter()
+            .map(|arg| arg.to_string())
+            .collect();
+        Config::from_args(&args).unwrap()
+    }
+
+    // a source, and where its book goes
+    fn source_and_dest(dir: &Path) -> (PathBuf, String) {
+        let source = dir.join("vol1.zip");
+        std::fs::write(&source, "source").unwrap();
+        let dest = dir.join("vol1.cbz").to_string_lossy().into_owned();
+        (source, dest)
+    }
+
+    fn write_book(dest: &str, comment: &str) {
+        let mut writer = ::zip::ZipWriter::new(std::fs::File::create(dest).unwrap());
+        writer.set_comment(comment);
+        writer.finish().unwrap();
+    }
+
+    #[test]
+    fn claim_dest_skips_or_overwrites() {
+        let dir = tempfile::tempdir().unwrap();
+        let (source, dest) = source_and_dest(dir.path());
+        write_book(&dest, "");
+
+        let report = RunReport::default();
+        assert!(claim_dest(dest.clone(), &config("skip"), Some(&source), &report).is_none());
+        let (claimed, fingerprint) =
+            claim_dest(dest.clone(), &config("overwrite"), Some(&source), &report).unwrap();
+        assert_eq!(claimed, dest);
+        assert!(fingerprint.is_some());
+    }
+
+    #[test]
+    fn claim_dest_renames() {
+        let dir = tempfile::tempdir().unwrap();
+        let (source, dest) = source_and_dest(dir.path());
+        write_book(&dest, "");
+
+        let (claimed, _) = claim_dest(
+            dest,
+            &config("rename"),
+            Some(&source),
+            &RunReport::default(),
+        )
+        .unwrap();
+        assert_eq!(Path::new(&claimed), dir.path().join("vol1_1.cbz"));
+    }
+
+    #[test]
+    fn claim_dest_compares_fingerprints() {
+        let dir = tempfile::tempdir().unwrap();
+        let (source, dest) = source_and_dest(dir.path());
+        let config = config("compare");
+        let fingerprint = helper::fingerprint(&source, &config.output_settings()).unwrap();
+
+        write_book(&dest, &format!("{FINGERPRINT_PREFIX}{fingerprint}"));
+        assert!(claim_dest(dest.clone(), &config, Some(&source), &RunReport::default()).is_none());
+
+        write_book(&dest, &format!("{FINGERPRINT_PREFIX}0000"));
+        let (claimed, _) =
+            claim_dest(dest.clone(), &config, Some(&source), &RunReport::default()).unwrap();
+        assert_eq!(claimed, dest);
+    }
+
+    #[test]
+    fn claim_dest_within_a_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let (source, dest) = source_and_dest(dir.path());
+        // `vol1.7z` maps to the same book, nothing is written yet
+        let other = dir.path().join("vol1.7z");
+        std::fs::write(&other, "other").unwrap();
+
+        let report = RunReport::default();
+        let (claimed, _) =
+            claim_dest(dest.clone(), &config("skip"), Some(&source), &report).unwrap();
+        assert_eq!(claimed, dest);
+        assert!(claim_dest(dest.clone(), &config("skip"), Some(&other), &report).is_none());
+        let (claimed, _) = claim_dest(dest, &config("overwrite"), Some(&other), &report).unwrap();
+        assert_eq!(Path::new(&claimed), dir.path().join("vol1_1.cbz"));
+    }
+}
@@ -57,6 +57,8 @@ pub enum MyError {
     Parse(std::num::ParseIntError),
     Zip(ZipError),
     AsyncZip(async_zip::error::ZipError),
+    SevenZ(sevenz_rust::Error),
+    Password(String),
     Custom(CustomError),
 }
 
@@ -67,6 +69,8 @@ impl fmt::Display for MyError {
             MyError::Parse(ref err) => write!(f, "Parse error: {err}"),
             MyError::Zip(ref err) => write!(f, "Zip Lib error: {err}"),
             MyError::AsyncZip(ref err) => write!(f, "Async Zip Lib error: {err}"),
+            MyError::SevenZ(ref err) => write!(f, "7z Lib error: {err}"),
+            MyError::Password(ref msg) => write!(f, "Password error: {msg}"),
             MyError::Custom(ref err) => write!(f, "custom error: {err}",),
         }
     }
@@ -79,6 +83,8 @@ impl std::error::Error for MyError {
             MyError::Parse(ref err) => Some(err),
             MyError::Zip(ref err) => Some(err),
             MyError::AsyncZip(ref err) => Some(err),
+            MyError::SevenZ(ref err) => Some(err),
+            MyError::Password(_) => None,
             MyError::Custom(ref err) => Some(err),
         }
     }
@@ -104,6 +110,11 @@ impl From<async_zip::error::ZipError> for MyError {
         MyError::AsyncZip(err)
     }
 }
+impl From<sevenz_rust::Error> for MyError {
+    fn from(err: sevenz_rust::Error) -> MyError {
+        MyError::SevenZ(err)
+    }
+}
 impl From<CustomError> for MyError {
     fn from(err: CustomError) -> MyError {
         MyError::Custom(err)
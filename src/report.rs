@@ -0,0 +1,71 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::helper::{self, NameSource};
+
+/// What happened to one book.
+#[derive(Clone, Debug, Default)]
+pub struct BookReport {
+    pub source: String,
+    pub dest: String,
+    /// false if the book could not be written, its source is left as it was
+    pub written: bool,
+    /// (original, new) page paths inside the book, converted and renumbered pages
+    pub renamed: Vec<(PathBuf, PathBuf)>,
+    /// decoded entry names of a zip book and where each came from
+    pub names: Vec<(String, NameSource)>,
+}
+
+/// Collected from every book thread, printed once at the end of the run.
+#[derive(Debug, Default)]
+pub struct RunReport {
+    books: Mutex<Vec<BookReport>>,
+    /// outputs taken by a book of this run, written or not yet
+    claimed: Mutex<HashSet<PathBuf>>,
+}
+
+impl RunReport {
+    // false if another book of this run took `dest` first
+    pub fn claim(&self, dest: &Path) -> bool {
+        match self.claimed.lock() {
+            Ok(mut claimed) => claimed.insert(dest.to_path_buf()),
+            Err(e) => e.into_inner().insert(dest.to_path_buf()),
+        }
+    }
+
+    pub fn add(&self, book: BookReport) {
+        if let Ok(mut books) = self.books.lock() {
+            books.push(book);
+        }
+    }
+
+    pub fn print(&self) {
+        let Ok(books) = self.books.lock() else {
+            return;
+        };
+        println!("[report] {} book(s)", books.len());
+        for book in books.iter() {
+            if book.written {
+                println!("[report] {} -> {}", book.source, book.dest);
+            } else {
+                println!("[report] {} -> {} failed", book.source, book.dest);
+            }
+            for (old, new) in &book.renamed {
+                println!("[report]     {} -> {}", old.display(), new.display());
+            }
+            // plain ascii names were never in doubt
+            for (name, source) in &book.names {
+                if *source != NameSource::Ascii {
+                    println!("[report]     name {name} ({source})");
+                }
+            }
+        }
+        if let Some(peak) = helper::peak_memory() {
+            println!(
+                "[report] peak memory {:.1} MiB",
+                peak as f64 / (1024.0 * 1024.0)
+            );
+        }
+    }
+}
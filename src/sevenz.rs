@@ -0,0 +1,62 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::time::SystemTime;
+
+use sevenz_rust::{Password, SevenZReader};
+
+use crate::{helper, MyError};
+
+pub(crate) fn un7z_inner(path: &Path, out_dir: &Path) -> Result<HashMap<String, u32>, MyError> {
+    let mut ret: HashMap<String, u32> = HashMap::new();
+    let mut archive = SevenZReader::open(path, Password::empty())?;
+
+    archive.for_each_entries(|entry, reader| {
+        // 7z stores names as UTF-16, no charset guessing needed, only separator normalizing
+        let entry_name = entry.name().replace('\\', "/");
+
+        helper::validate_file_name(&entry_name)
+            .map_err(|e| sevenz_rust::Error::other(e.to_string()))?;
+
+        let out_path = out_dir.join(&entry_name);
+
+        if entry.is_directory() {
+            println!("[un7z_inner] mkdir -p {:?}", out_path);
+            std::fs::create_dir_all(&out_path)?;
+        } else {
+            // statistic file ext name
+            *ret.entry(helper::get_file_ext_or_itself(&entry_name).to_string())
+                .or_insert(0) += 1u32;
+
+            if let Some(parent) = out_path.parent() {
+                if !parent.exists() {
+                    println!("[un7z_inner] mkdir -p {:?}", parent);
+                    std::fs::create_dir_all(parent)?;
+                }
+            }
+
+            let time = std::time::Instant::now();
+            let mut out_file = std::fs::File::create(&out_path)?;
+            io::copy(reader, &mut out_file)?;
+            drop(out_file);
+            let mtime = entry
+                .has_last_modified_date
+                .then(|| SystemTime::from(entry.last_modified_date()));
+            // p7zip keeps unix permissions in the high half behind this flag
+            let mode = (entry.has_windows_attributes && entry.windows_attributes() & 0x8000 != 0)
+                .then(|| entry.windows_attributes() >> 16);
+            helper::restore_file_attrs(&out_path, mtime, mode)
+                .map_err(|e| sevenz_rust::Error::other(e.to_string()))?;
+            println!(
+                "[un7z_inner] copy {:?} -> {:?} ({:.2} ms unzip & I/O)",
+                entry_name,
+                out_path,
+                time.elapsed().as_micros() as f64 / 1000.0
+            );
+        }
+
+        Ok(true)
+    })?;
+
+    Ok(ret)
+}
@@ -0,0 +1,314 @@
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use flate2::read::GzDecoder;
+use tar::{Archive, Builder, EntryType, Header, HeaderMode};
+use tokio::fs::{self, File};
+use walkdir::{DirEntry, WalkDir};
+
+use crate::constant::FINGERPRINT_PREFIX;
+use crate::helper::NameEncoding;
+use crate::{helper, CustomError, MyError};
+
+pub async fn tar_dir(
+    src_dir: &str,
+    dst_file: &str,
+    filter: Option<Box<dyn FnMut(&DirEntry) -> bool>>,
+    deterministic: bool,
+    // only with `deterministic`, tar's own fixed mtime otherwise
+    source_mtime: Option<SystemTime>,
+    fingerprint: Option<&str>,
+) -> Result<(), MyError> {
+    if !Path::new(src_dir).is_dir() {
+        return Err(MyError::Io(io::Error::from(io::ErrorKind::NotFound)));
+    }
+
+    // check if dst dir not found
+    let dst_parent = Path::new(dst_file)
+        .parent()
+        .ok_or(CustomError::new(&format!(
+            "cannot find parent dir from {dst_file}"
+        )))?;
+    if !dst_parent.exists() {
+        fs::create_dir_all(dst_parent).await?;
+    }
+
+    // same as zip_dir, written aside and renamed over dst file once verified
+    let partial = helper::partial_path(Path::new(dst_file));
+    let file = File::create(&partial).await?;
+
+    let mut walkdir = WalkDir::new(src_dir);
+    if deterministic {
+        walkdir = walkdir.sort_by_file_name();
+    }
+
+    let it = walkdir.into_iter().filter_map(|e| e.ok());
+    let mut it = it.filter(if let Some(filter) = filter {
+        filter
+    } else {
+        Box::new(|_: &DirEntry| true)
+    });
+
+    let written = tar_dir_inner(
+        &mut it,
+        src_dir,
+        file.into_std().await,
+        deterministic,
+        source_mtime.filter(|_| deterministic),
+        fingerprint,
+    )
+    .and_then(|file| Ok(file.sync_all()?))
+    .and_then(|_| verify_tar(&partial))
+    .and_then(|_| helper::commit_partial(&partial, Path::new(dst_file)));
+    if written.is_err() {
+        let _ = fs::remove_file(&partial).await;
+    }
+
+    written
+}
+
+// read every entry back, header checksums and sizes included
+fn verify_tar(path: &Path) -> Result<(), MyError> {
+    let mut archive = Archive::new(std::fs::File::open(path)?);
+    for entry in archive.entries()? {
+        io::copy(&mut entry?, &mut io::sink())?;
+    }
+    Ok(())
+}
+
+fn tar_dir_inner(
+    it: &mut dyn Iterator<Item = DirEntry>,
+    prefix: &str,
+    writer: std::fs::File,
+    deterministic: bool,
+    source_mtime: Option<SystemTime>,
+    fingerprint: Option<&str>,
+) -> Result<std::fs::File, MyError> {
+    let mut tar = Builder::new(writer);
+    if deterministic {
+        // fixed mtime, owner and permissions
+        tar.mode(HeaderMode::Deterministic);
+    }
+
+    // tar has no archive comment, a pax global header is the closest thing
+    if let Some(fingerprint) = fingerprint {
+        let record = pax_record("comment", &format!("{FINGERPRINT_PREFIX}{fingerprint}"));
+        let mut header = Header::new_ustar();
+        header.set_entry_type(EntryType::XGlobalHeader);
+        header.set_path("pax_global_header")?;
+        header.set_size(record.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        tar.append(&header, record.as_bytes())?;
+    }
+
+    for entry in it {
+        let path = entry.path();
+        let name = path
+            .strip_prefix(Path::new(prefix))
+            .ok()
+            .ok_or(CustomError::new(&format!(
+                "strip_prefix path {prefix} failed"
+            )))?;
+
+        if path.is_file() {
+            let mut file = helper::open_extracted(path)?;
+            match source_mtime {
+                Some(mtime) => {
+                    let mut header = deterministic_header(&file.metadata()?, mtime);
+                    tar.append_data(&mut header, name, &mut file)?;
+                }
+                None => tar.append_file(name, &mut file)?,
+            }
+        } else if !name.as_os_str().is_empty() {
+            // Only if not root!
+            match source_mtime {
+                Some(mtime) => {
+                    let mut header = deterministic_header(&std::fs::metadata(path)?, mtime);
+                    tar.append_data(&mut header, name, io::empty())?;
+                }
+                None => tar.append_dir(name, path)?,
+            }
+        }
+    }
+
+    Ok(tar.into_inner()?)
+}
+
+// what `HeaderMode::Deterministic` writes, with the source mtime in place of tar's own
+fn deterministic_header(metadata: &std::fs::Metadata, mtime: SystemTime) -> Header {
+    let mut header = Header::new_gnu();
+    header.set_metadata_in_mode(metadata, HeaderMode::Deterministic);
+    header.set_mtime(
+        mtime
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0),
+    );
+    header
+}
+
+// `<length> <key>=<value>\n`, the length counting its own digits
+fn pax_record(key: &str, value: &str) -> String {
+    let body = format!(" {key}={value}\n");
+    let mut len = body.len() + 1;
+    while len != body.len() + len.to_string().len() {
+        len = body.len() + len.to_string().len();
+    }
+    format!("{len}{body}")
+}
+
+pub(crate) fn untar_inner(
+    path: &Path,
+    out_dir: &Path,
+    gzip: bool,
+    encoding: &NameEncoding,
+) -> Result<HashMap<String, u32>, MyError> {
+    let mut ret: HashMap<String, u32> = HashMap::new();
+    let reader = std::fs::File::open(path)?;
+    let reader: Box<dyn Read> = if gzip {
+        Box::new(GzDecoder::new(reader))
+    } else {
+        Box::new(reader)
+    };
+    let mut archive = Archive::new(reader);
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let raw_name = entry.path_bytes().into_owned();
+
+        // tar has no encoding flag, names from old scanners are often legacy encoded
+        let entry_name = match String::from_utf8(raw_name) {
+            Ok(name) => name,
+            Err(e) => helper::decode_zip_filename(e.as_bytes(), false, &[], encoding)?.0,
+        };
+
+        helper::validate_file_name(&entry_name)?;
+
+        let out_path = out_dir.join(&entry_name);
+        let entry_type = entry.header().entry_type();
+
+        if entry_type.is_dir() {
+            println!("[untar_inner] mkdir -p {:?}", out_path);
+            std::fs::create_dir_all(&out_path)?;
+        } else if entry_type.is_file() {
+            // statistic file ext name
+            *ret.entry(helper::get_file_ext_or_itself(&entry_name).to_string())
+                .or_insert(0) += 1u32;
+
+            if let Some(parent) = out_path.parent() {
+                if !parent.exists() {
+                    println!("[untar_inner] mkdir -p {:?}", parent);
+                    std::fs::create_dir_all(parent)?;
+                }
+            }
+
+            let time = std::time::Instant::now();
+            let mut out_file = std::fs::File::create(&out_path)?;
+            io::copy(&mut entry, &mut out_file)?;
+            drop(out_file);
+            let mtime = entry
+                .header()
+                .mtime()
+                .ok()
+                .map(|secs| UNIX_EPOCH + Duration::from_secs(secs));
+            helper::restore_file_attrs(&out_path, mtime, entry.header().mode().ok())?;
+            println!(
+                "[untar_inner] copy {:?} -> {:?} ({:.2} ms untar & I/O)",
+                entry_name,
+                out_path,
+                time.elapsed().as_micros() as f64 / 1000.0
+            );
+        } else {
+            // links, devices and the like have no place in a comic book
+            println!("[untar_inner] skip {:?} ({:?})", entry_name, entry_type);
+        }
+    }
+
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the length prefix must equal the length of the whole record
+    fn check(record: &str) {
+        let (len, _) = record.split_once(' ').unwrap();
+        assert_eq!(len.parse::<usize>().unwrap(), record.len(), "{record:?}");
+    }
+
+    #[test]
+    fn pax_record_length_counts_itself() {
+        assert_eq!(pax_record("path", "a.jpg"), "14 path=a.jpg\n");
+        check(&pax_record("path", "第1話/001.jpg"));
+        // the record grows a digit right at the boundary: 98 bytes of body make 101
+        for len in 0..1100 {
+            check(&pax_record("path", &"x".repeat(len)));
+        }
+    }
+
+    #[tokio::test]
+    async fn deterministic_tar_takes_the_source_mtime() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("book");
+        std::fs::create_dir_all(src.join("c01")).unwrap();
+        std::fs::write(src.join("c01/001.jpg"), b"page").unwrap();
+        let dst = dir.path().join("book.cbt");
+        let mtime = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        tar_dir(
+            src.to_str().unwrap(),
+            dst.to_str().unwrap(),
+            None,
+            true,
+            Some(mtime),
+            None,
+        )
+        .await
+        .unwrap();
+
+        let mut archive = Archive::new(std::fs::File::open(&dst).unwrap());
+        let mut names = vec![];
+        for entry in archive.entries().unwrap() {
+            let entry = entry.unwrap();
+            assert_eq!(entry.header().mtime().unwrap(), 1_600_000_000);
+            assert_eq!(entry.header().uid().unwrap(), 0);
+            names.push(entry.path().unwrap().to_string_lossy().into_owned());
+        }
+        assert_eq!(names, ["c01", "c01/001.jpg"]);
+    }
+
+    // `Header::set_path` refuses both, the name goes into the header as it is
+    fn tar_with_name(path: &Path, name: &str) {
+        let mut header = Header::new_ustar();
+        header.as_old_mut().name[..name.len()].copy_from_slice(name.as_bytes());
+        header.set_size(4);
+        header.set_mode(0o644);
+        header.set_cksum();
+        let mut builder = Builder::new(std::fs::File::create(path).unwrap());
+        builder.append(&header, &b"page"[..]).unwrap();
+        builder.finish().unwrap();
+    }
+
+    #[test]
+    fn untar_rejects_paths_out_of_the_book() {
+        let dir = tempfile::tempdir().unwrap();
+        let out_dir = dir.path().join("out/book");
+        std::fs::create_dir_all(&out_dir).unwrap();
+        let absolute = dir.path().join("abs.jpg").to_string_lossy().into_owned();
+        for name in ["../evil.jpg", "c01/../../evil.jpg", &absolute] {
+            let archive = dir.path().join("book.tar");
+            tar_with_name(&archive, name);
+            assert!(
+                untar_inner(&archive, &out_dir, false, &NameEncoding::default()).is_err(),
+                "{name}"
+            );
+        }
+        assert!(!dir.path().join("out/evil.jpg").exists());
+        assert!(!dir.path().join("evil.jpg").exists());
+        assert!(!Path::new(&absolute).exists());
+        assert_eq!(std::fs::read_dir(&out_dir).unwrap().count(), 0);
+    }
+}
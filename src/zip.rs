@@ -1,49 +1,71 @@
-use async_zip::Compression;
+use async_zip::{Compression, DeflateOption, ZipDateTime, ZipEntry, ZipEntryBuilder};
 use chalk_rs::Chalk;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::io::{self, Read, Seek, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::SystemTime;
 use tokio::fs::{self, File};
 use tokio::sync::Mutex;
 use walkdir::{DirEntry, WalkDir};
 use zip::result::ZipError;
+use zip::unstable::write::FileOptionsExt;
 use zip::write::FileOptions;
 use zip::ZipArchive;
 
-use crate::constant::METHOD_STORED;
-use crate::{helper, CustomError, MyError};
+use crate::config::ZipWriterKind;
+use crate::constant::{
+    ARCHIVE_SNIFF_LEN, COPY_BUF_LEN, DATA_DESCRIPTOR_SIG, EXTENDED_TIMESTAMP_ID,
+    FINGERPRINT_PREFIX, LOCAL_HEADER_SIG, METHOD_STORED, SNIFF_LEN, TAR_GZ_EXT, ZIP64_EXTRA_ID,
+};
+use crate::helper::{ArchiveKind, NameEncoding, NameSource};
+use crate::inspect::ArchiveDiagnostic;
+use crate::{helper, sevenz, tar, CustomError, MyError};
+
+#[derive(Clone, Debug)]
+pub struct ZipOptions {
+    /// method for entries that are not already compressed
+    pub method: zip::CompressionMethod,
+    /// method specific level, `None` for the method's default
+    pub level: Option<i32>,
+    /// byte-identical output for the same input: sorted entries and this mtime on every entry
+    pub deterministic: Option<zip::DateTime>,
+    /// stored as the archive comment, see `helper::fingerprint`
+    pub fingerprint: Option<String>,
+    pub writer: ZipWriterKind,
+    /// ZipCrypto password for every entry, only honored by the sync writer
+    pub encrypt: Option<String>,
+}
 
 pub async fn zip_dir(
     src_dir: &str,
     dst_file: &str,
     filter: Option<Box<dyn FnMut(&DirEntry) -> bool>>,
+    zip_options: ZipOptions,
 ) -> Result<(), MyError> {
     if !Path::new(src_dir).is_dir() {
         return Err(MyError::Zip(ZipError::FileNotFound));
     }
 
     // check if dst dir not found
-    if !Path::new(dst_file)
+    let dst_parent = Path::new(dst_file)
         .parent()
         .ok_or(CustomError::new(&format!(
             "cannot find parent dir from {dst_file}"
-        )))?
-        .exists()
-    {
-        fs::create_dir_all(&dst_file).await?;
-    }
-
-    // check if dst file is already exist
-    if Path::new(dst_file).exists() {
-        return Err(MyError::Custom(CustomError::new(&format!(
-            "dst_file {dst_file} is already exist"
-        ))));
+        )))?;
+    if !dst_parent.exists() {
+        fs::create_dir_all(dst_parent).await?;
     }
 
-    let file = File::create(Path::new(dst_file)).await?;
+    // an existing dst file is only replaced once the new one is complete,
+    // whether to replace it at all is up to the caller
+    let partial = helper::partial_path(Path::new(dst_file));
+    let file = File::create(&partial).await?;
 
-    let walkdir = WalkDir::new(src_dir);
+    let mut walkdir = WalkDir::new(src_dir);
+    if zip_options.deterministic.is_some() {
+        walkdir = walkdir.sort_by_file_name();
+    }
 
     let it = walkdir.into_iter().filter_map(|e| e.ok());
     let mut it = it.filter(if let Some(filter) = filter {
@@ -52,22 +74,487 @@ pub async fn zip_dir(
         Box::new(|_: &DirEntry| true)
     });
 
-    zip_dir_inner(&mut it, src_dir, file.into_std().await, METHOD_STORED)?;
+    let written = match zip_options.writer {
+        ZipWriterKind::Async => {
+            let mut file = file;
+            match zip_dir_inner_async(&mut it, src_dir, &mut file, &zip_options).await {
+                Ok(_) => Ok(file.sync_all().await?),
+                Err(e) => Err(e),
+            }
+        }
+        ZipWriterKind::Sync => zip_dir_inner(&mut it, src_dir, file.into_std().await, &zip_options)
+            .and_then(|file| Ok(file.sync_all()?)),
+    };
+    let written = match written {
+        Ok(_) => commit_zip(&partial, dst_file, zip_options.encrypt.clone()).await,
+        Err(e) => Err(e),
+    };
+    if written.is_err() {
+        let _ = fs::remove_file(&partial).await;
+    }
+
+    written
+}
+
+// reading the whole book back is std I/O, kept off the runtime threads
+async fn commit_zip(
+    partial: &Path,
+    dst_file: &str,
+    password: Option<String>,
+) -> Result<(), MyError> {
+    let partial = partial.to_path_buf();
+    let dst_file = PathBuf::from(dst_file);
+    tokio::task::spawn_blocking(move || {
+        verify_zip(&partial, password.as_deref())
+            .and_then(|_| helper::commit_partial(&partial, &dst_file))
+    })
+    .await
+    .unwrap_or_else(|e| Err(MyError::Io(io::Error::other(e))))
+}
 
+/// Stages every entry of `transcode` goes through on its way into the book.
+pub struct Pipeline<'a> {
+    /// an entry only usable extracted (a nested archive), the whole book falls back. Gets
+    /// the name and the first `ARCHIVE_SNIFF_LEN` bytes
+    pub needs_extraction: &'a dyn Fn(&str, &[u8]) -> bool,
+    pub keep: &'a dyn Fn(&str) -> bool,
+    /// name of the transformed entry, `None` copies it through as is
+    pub transform_name: &'a dyn Fn(&str) -> Option<String>,
+    /// turns the spilled entry (first path) into the second path
+    pub transform: &'a dyn Fn(&Path, &Path) -> Result<(), MyError>,
+}
+
+// zip to zip without extracting: unchanged entries are copied as their compressed bytes
+// (crc and sizes from the source central directory), only entries to transform are
+// spilled to a temp dir and encoded again. The extended timestamp of a raw copy is written
+// again, see `raw_copy_with_timestamp`. Always the `zip` crate writer, async_zip has no raw
+// copy. Returns the (old, new) entry names of every transformed entry and where
+// every entry name was decoded from
+pub async fn transcode(
+    path: &str,
+    dst_file: &str,
+    pipeline: &Pipeline<'_>,
+    zip_options: &ZipOptions,
+    encoding: &NameEncoding,
+) -> Result<(Vec<(PathBuf, PathBuf)>, Vec<(String, NameSource)>), MyError> {
+    let mut archive = ZipArchive::new(std::fs::File::open(path)?)?;
+    let encoding = zip_archive_encoding(&mut archive, encoding)?;
+
+    // all names are known before the first byte is written
+    let mut entries = vec![];
+    let mut names = vec![];
+    for index in 0..archive.len() {
+        // encrypted entries need a password, that is up to the extraction path
+        let mut file = archive.by_index(index)?;
+        let (name, source) = helper::decode_zip_filename(
+            file.name_raw(),
+            utf8_flag(&file),
+            file.extra_data(),
+            &encoding,
+        )?;
+        helper::validate_file_name(&name)?;
+        names.push((name.clone(), source));
+        let mut head = Vec::with_capacity(ARCHIVE_SNIFF_LEN);
+        (&mut file)
+            .take(ARCHIVE_SNIFF_LEN as u64)
+            .read_to_end(&mut head)?;
+        if (pipeline.needs_extraction)(&name, &head) {
+            return Err(MyError::from(CustomError::new(&format!(
+                "{name} needs extraction"
+            ))));
+        }
+        if name.ends_with('/') || !(pipeline.keep)(&name) {
+            continue;
+        }
+        let timestamp =
+            helper::find_extra_field(file.extra_data(), EXTENDED_TIMESTAMP_ID).is_some();
+        entries.push((index, name, zip_file_attrs(&file), timestamp));
+    }
+    // a raw copy keeps the source clock and permissions, not what deterministic pins
+    let raw_copy = zip_options.deterministic.is_none();
+    if !raw_copy {
+        entries.sort_by(|a, b| a.1.cmp(&b.1));
+    }
+
+    let dst_parent = Path::new(dst_file)
+        .parent()
+        .ok_or(CustomError::new(&format!(
+            "cannot find parent dir from {dst_file}"
+        )))?;
+    if !dst_parent.exists() {
+        fs::create_dir_all(dst_parent).await?;
+    }
+    let partial = helper::partial_path(Path::new(dst_file));
+
+    let mut renamed = vec![];
+    let written = (|| -> Result<(), MyError> {
+        // read back by `restore_raw_copies`
+        let partial_file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&partial)?;
+        let mut zip = zip::ZipWriter::new(partial_file);
+        let mut buffer = vec![0u8; COPY_BUF_LEN];
+        let mut spill_dir = None;
+        let mut stamped = vec![];
+        // one entry written for each
+        for (ordinal, (index, name, attrs, timestamp)) in entries.into_iter().enumerate() {
+            let Some(new_name) = (pipeline.transform_name)(&name) else {
+                let timestamp = attrs
+                    .0
+                    .filter(|_| timestamp)
+                    .and_then(helper::extended_timestamp_field);
+                match (raw_copy, timestamp) {
+                    (true, Some(timestamp)) => {
+                        let file = archive.by_index_raw(index)?;
+                        if let Some(copy) =
+                            raw_copy_with_timestamp(&mut zip, file, name, ordinal, &timestamp)?
+                        {
+                            stamped.push(copy);
+                        }
+                    }
+                    (true, None) => zip.raw_copy_file_rename(archive.by_index_raw(index)?, name)?,
+                    (false, _) => {
+                        let mut file = archive.by_index(index)?;
+                        write_entry(&mut zip, name, &mut file, attrs, zip_options, &mut buffer)?;
+                    }
+                }
+                continue;
+            };
+
+            let dir = match &spill_dir {
+                Some(dir) => dir,
+                None => spill_dir.insert(tempfile::tempdir()?),
+            };
+            // the transform may go by the extension
+            let from = dir
+                .path()
+                .join(format!("{index}.{}", helper::get_file_ext_or_itself(&name)));
+            let to = dir.path().join(format!(
+                "{index}.{}",
+                helper::get_file_ext_or_itself(&new_name)
+            ));
+            io::copy(
+                &mut archive.by_index(index)?,
+                &mut std::fs::File::create(&from)?,
+            )?;
+
+            let time = std::time::Instant::now();
+            match (pipeline.transform)(&from, &to) {
+                Ok(_) => {
+                    println!(
+                        "[transcode] convert {name} to {new_name} ({} ms)",
+                        time.elapsed().as_millis()
+                    );
+                    let mut converted = std::fs::File::open(&to)?;
+                    write_entry(
+                        &mut zip,
+                        new_name.clone(),
+                        &mut converted,
+                        attrs,
+                        zip_options,
+                        &mut buffer,
+                    )?;
+                    renamed.push((PathBuf::from(&name), PathBuf::from(new_name)));
+                }
+                Err(e) => {
+                    // the page stays as it was
+                    eprintln!("[transcode] convert {name} failed: {e}");
+                    let mut spilled = std::fs::File::open(&from)?;
+                    write_entry(
+                        &mut zip,
+                        name,
+                        &mut spilled,
+                        attrs,
+                        zip_options,
+                        &mut buffer,
+                    )?;
+                }
+            }
+            let _ = std::fs::remove_file(&from);
+            let _ = std::fs::remove_file(&to);
+        }
+
+        if let Some(fingerprint) = &zip_options.fingerprint {
+            zip.set_comment(format!("{FINGERPRINT_PREFIX}{fingerprint}"));
+        }
+        let mut file = zip.finish()?;
+        restore_raw_copies(&mut file, &stamped)?;
+        Ok(file.sync_all()?)
+    })();
+
+    let written = match written {
+        Ok(_) => commit_zip(&partial, dst_file, zip_options.encrypt.clone()).await,
+        Err(e) => Err(e),
+    };
+    if written.is_err() {
+        let _ = fs::remove_file(&partial).await;
+    }
+
+    written.map(|_| (renamed, names))
+}
+
+/// A raw copy written as a stored entry, see `raw_copy_with_timestamp`.
+struct StampedCopy {
+    /// position among the entries of the output
+    ordinal: usize,
+    header_start: u64,
+    method: u16,
+    crc32: u32,
+    size: u32,
+}
+
+// the raw copy of the zip crate leaves extra data out. Here the compressed bytes go into a
+// stored entry that starts with the extended timestamp, and `restore_raw_copies` puts the
+// method, crc and size of the source back once the archive is finished. `None` is a plain
+// raw copy, for entries too large for that
+fn raw_copy_with_timestamp<W: Write + Seek>(
+    zip: &mut zip::ZipWriter<W>,
+    mut file: zip::read::ZipFile,
+    name: String,
+    ordinal: usize,
+    timestamp: &[u8],
+) -> Result<Option<StampedCopy>, MyError> {
+    if file.size().max(file.compressed_size()) >= u32::MAX as u64 {
+        zip.raw_copy_file_rename(file, name)?;
+        return Ok(None);
+    }
+    let mut options = FileOptions::default()
+        .compression_method(METHOD_STORED)
+        .last_modified_time(file.last_modified());
+    if let Some(mode) = file.unix_mode() {
+        options = options.unix_permissions(mode);
+    }
+    #[allow(deprecated)]
+    let method = file.compression().to_u16();
+    let (crc32, size) = (file.crc32(), file.size() as u32);
+    let name_len = name.len() as u64;
+    let data_start = zip.start_file_with_extra_data(name, options)?;
+    zip.write_all(&EXTENDED_TIMESTAMP_ID.to_le_bytes())?;
+    zip.write_all(&(timestamp.len() as u16).to_le_bytes())?;
+    zip.write_all(timestamp)?;
+    zip.end_extra_data()?;
+    // a raw reader, these are the compressed bytes
+    io::copy(&mut file, zip)?;
+    Ok(Some(StampedCopy {
+        ordinal,
+        // the fixed 30 bytes and the name, there is no zip64 field below 4 GiB
+        header_start: data_start - 30 - name_len,
+        method,
+        crc32,
+        size,
+    }))
+}
+
+// the fields `raw_copy_with_timestamp` wrote for a stored entry have a fixed width in both
+// the local header and the central directory, nothing else moves
+fn restore_raw_copies(file: &mut std::fs::File, copies: &[StampedCopy]) -> Result<(), MyError> {
+    if copies.is_empty() {
+        return Ok(());
+    }
+    // version needed to extract per method (APPNOTE 4.4.3.2), never below the 2.0 the zip
+    // crate writes for every entry
+    let version = |method: u16| match method {
+        9 => 21u16,              // deflate64
+        10 => 25,                // pkware dcl implode
+        12 => 46,                // bzip2
+        14 | 93 | 95 | 98 => 63, // lzma, zstd, xz, ppmd
+        99 => 51,                // winzip aes
+        _ => 20,
+    };
+    for copy in copies {
+        let mut header = [0u8; 26];
+        file.seek(io::SeekFrom::Start(copy.header_start))?;
+        file.read_exact(&mut header)?;
+        header[4..6].copy_from_slice(&version(copy.method).to_le_bytes());
+        header[8..10].copy_from_slice(&copy.method.to_le_bytes());
+        header[14..18].copy_from_slice(&copy.crc32.to_le_bytes());
+        header[22..26].copy_from_slice(&copy.size.to_le_bytes());
+        file.seek(io::SeekFrom::Start(copy.header_start))?;
+        file.write_all(&header)?;
+    }
+
+    let (cd_start, cd_len) = central_directory(file)?;
+    let mut cd = vec![0u8; cd_len];
+    file.seek(io::SeekFrom::Start(cd_start))?;
+    file.read_exact(&mut cd)?;
+    let mut copies = copies.iter().peekable();
+    let mut pos = 0;
+    for ordinal in 0.. {
+        let Some(copy) = copies.peek() else {
+            break;
+        };
+        let record = cd
+            .get(pos..pos + 46)
+            .filter(|record| record[..4] == [0x50, 0x4b, 0x01, 0x02])
+            .ok_or_else(|| CustomError::new("central directory ends early"))?;
+        let len = 46
+            + u16::from_le_bytes([record[28], record[29]]) as usize
+            + u16::from_le_bytes([record[30], record[31]]) as usize
+            + u16::from_le_bytes([record[32], record[33]]) as usize;
+        if copy.ordinal == ordinal {
+            let record = &mut cd[pos..pos + 46];
+            record[6..8].copy_from_slice(&version(copy.method).to_le_bytes());
+            record[10..12].copy_from_slice(&copy.method.to_le_bytes());
+            record[16..20].copy_from_slice(&copy.crc32.to_le_bytes());
+            record[24..28].copy_from_slice(&copy.size.to_le_bytes());
+            copies.next();
+        }
+        pos += len;
+    }
+    file.seek(io::SeekFrom::Start(cd_start))?;
+    file.write_all(&cd)?;
+    Ok(())
+}
+
+// (offset, size) of the central directory of a zip the zip crate just wrote, from its end
+// record or the zip64 one past 4 GiB
+fn central_directory(file: &mut std::fs::File) -> Result<(u64, usize), MyError> {
+    let len = file.seek(io::SeekFrom::End(0))?;
+    // the end record, a zip64 locator before it and at most a 64 KiB comment after it
+    let tail_len = len.min(22 + 20 + u16::MAX as u64);
+    let mut tail = vec![0u8; tail_len as usize];
+    file.seek(io::SeekFrom::Start(len - tail_len))?;
+    file.read_exact(&mut tail)?;
+    let eocd = tail
+        .windows(4)
+        .rposition(|w| w == [0x50, 0x4b, 0x05, 0x06])
+        .filter(|&eocd| eocd + 22 <= tail.len())
+        .ok_or_else(|| CustomError::new("end of central directory not found"))?;
+    let u32_at = |buf: &[u8], at: usize| {
+        u32::from_le_bytes([buf[at], buf[at + 1], buf[at + 2], buf[at + 3]])
+    };
+    let cd_len = u32_at(&tail, eocd + 12);
+    let cd_start = u32_at(&tail, eocd + 16);
+    if cd_start != u32::MAX && cd_len != u32::MAX {
+        return Ok((cd_start as u64, cd_len as usize));
+    }
+    let locator = eocd
+        .checked_sub(20)
+        .filter(|&locator| tail[locator..locator + 4] == [0x50, 0x4b, 0x06, 0x07])
+        .ok_or_else(|| CustomError::new("zip64 end of central directory locator not found"))?;
+    let u64_at = |buf: &[u8], at: usize| {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&buf[at..at + 8]);
+        u64::from_le_bytes(bytes)
+    };
+    let mut zip64_eocd = [0u8; 56];
+    file.seek(io::SeekFrom::Start(u64_at(&tail, locator + 8)))?;
+    file.read_exact(&mut zip64_eocd)?;
+    Ok((u64_at(&zip64_eocd, 48), u64_at(&zip64_eocd, 40) as usize))
+}
+
+// read every entry back, the reader checks each crc
+fn verify_zip(path: &Path, password: Option<&str>) -> Result<(), MyError> {
+    let mut zip = ZipArchive::new(std::fs::File::open(path)?)?;
+    for i in 0..zip.len() {
+        let mut file = match password {
+            Some(password) => zip
+                .by_index_decrypt(i, password.as_bytes())?
+                .map_err(|_| MyError::Password(format!("entry {i} of the written book")))?,
+            None => zip.by_index(i)?,
+        };
+        io::copy(&mut file, &mut io::sink())?;
+    }
     Ok(())
 }
 
+// also returns where every entry name was decoded from, empty for 7z and tar
 pub async fn unzip(
     path: String,
-    out_path: String,
-) -> Result<(HashMap<String, u32>, String, String), MyError> {
-    let reader = File::open(&path).await?;
+    passwords: &[String],
+    encoding: &NameEncoding,
+) -> Result<(HashMap<String, u32>, Vec<(String, NameSource)>, String), MyError> {
+    let tmp_dir = tempfile::tempdir()?;
+    println!("make tmp_dir {:?}", tmp_dir.path());
+
+    let mut names = vec![];
+    let ret = extract_into(
+        Path::new(&path),
+        tmp_dir.path(),
+        passwords,
+        encoding,
+        &mut names,
+    )
+    .await?;
 
+    let temp_path_str = tmp_dir
+        .into_path()
+        .as_os_str()
+        .to_string_lossy()
+        .into_owned();
+
+    Ok((ret, names, temp_path_str))
+}
+
+// stdin and pipes cannot seek, so there is no central directory to start from: entries are
+// read one local header at a time. The names only decode once all of them are known, so
+// every entry is staged under its index first and moved to its name at the end
+pub async fn unzip_stream<R>(
+    reader: R,
+    encoding: &NameEncoding,
+) -> Result<(HashMap<String, u32>, Vec<(String, NameSource)>, String), MyError>
+where
+    R: tokio::io::AsyncRead + Unpin,
+{
     let tmp_dir = tempfile::tempdir()?;
     println!("make tmp_dir {:?}", tmp_dir.path());
+    let stage_dir = tempfile::tempdir()?;
 
-    let ret = unzip_inner_async(reader, tmp_dir.path()).await?;
-    // unzip_inner(reader, tmp_dir.path()).await?;
+    let time = std::time::Instant::now();
+    let mut stream = StreamReader::new(reader);
+    let mut entries = vec![];
+    while let Some(entry) = read_stream_entry(&mut stream, stage_dir.path(), entries.len()).await? {
+        entries.push(entry);
+    }
+    // the central directory repeats what came before, the writer gets to finish anyway
+    tokio::io::copy(&mut stream.inner, &mut tokio::io::sink()).await?;
+    println!(
+        "[unzip_stream] {} entries ({:.2} ms)",
+        entries.len(),
+        time.elapsed().as_micros() as f64 / 1000.0
+    );
+
+    let mut encoding = encoding.clone();
+    if encoding.forced.is_none() {
+        encoding.archive = helper::archive_encoding(
+            entries
+                .iter()
+                .map(|entry| (entry.name.as_slice(), entry.extra.as_slice())),
+            &encoding.candidates,
+        )
+        .chosen;
+    }
+
+    let mut ret: HashMap<String, u32> = HashMap::new();
+    let mut names = vec![];
+    for (index, entry) in entries.iter().enumerate() {
+        let staged = stage_dir.path().join(index.to_string());
+        let Ok((decoded_entry_name, source)) =
+            helper::decode_zip_filename(&entry.name, entry.utf8, &entry.extra, &encoding)
+        else {
+            continue;
+        };
+        if helper::validate_file_name(&decoded_entry_name).is_err() {
+            continue;
+        }
+        let path = tmp_dir.path().join(&decoded_entry_name);
+        names.push((decoded_entry_name.clone(), source));
+        if decoded_entry_name.ends_with('/') {
+            fs::create_dir_all(&path).await?;
+            continue;
+        }
+        *ret.entry(helper::get_file_ext_or_itself(&decoded_entry_name))
+            .or_insert(0) += 1u32;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        fs::rename(&staged, &path).await?;
+        // the unix mode is only in the central directory
+        helper::restore_file_attrs(&path, entry.mtime, None)?;
+    }
 
     let temp_path_str = tmp_dir
         .into_path()
@@ -75,26 +562,502 @@ pub async fn unzip(
         .to_string_lossy()
         .into_owned();
 
-    Ok((
-        ret,
-        temp_path_str,
-        helper::get_out_zip_path(&path, &out_path)?,
-    ))
+    Ok((ret, names, temp_path_str))
+}
+
+/// An entry read off a stream, its data staged under its index.
+struct StreamEntry {
+    name: Vec<u8>,
+    extra: Vec<u8>,
+    utf8: bool,
+    mtime: Option<SystemTime>,
+}
+
+/// A buffered reader that hands back exactly what a parser did not use, a deflate stream
+/// ends wherever it ends and the data descriptor follows right after.
+struct StreamReader<R> {
+    inner: R,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: tokio::io::AsyncRead + Unpin> StreamReader<R> {
+    fn new(inner: R) -> Self {
+        StreamReader {
+            inner,
+            buf: vec![],
+            pos: 0,
+        }
+    }
+
+    fn available(&self) -> &[u8] {
+        &self.buf[self.pos..]
+    }
+
+    fn consume(&mut self, n: usize) {
+        self.pos += n;
+    }
+
+    // append more input to what is left, false at the end of the stream
+    async fn fill_more(&mut self) -> Result<bool, MyError> {
+        use tokio::io::AsyncReadExt;
+
+        self.buf.drain(..self.pos);
+        self.pos = 0;
+        let len = self.buf.len();
+        self.buf.resize(len + COPY_BUF_LEN, 0);
+        let n = self.inner.read(&mut self.buf[len..]).await?;
+        self.buf.truncate(len + n);
+        Ok(n > 0)
+    }
+
+    // fewer bytes at the end of the stream
+    async fn peek(&mut self, n: usize) -> Result<&[u8], MyError> {
+        while self.available().len() < n {
+            if !self.fill_more().await? {
+                break;
+            }
+        }
+        let end = (self.pos + n).min(self.buf.len());
+        Ok(&self.buf[self.pos..end])
+    }
+
+    async fn read_vec(&mut self, n: usize) -> Result<Vec<u8>, MyError> {
+        let data = self.peek(n).await?.to_vec();
+        if data.len() < n {
+            return Err(truncated());
+        }
+        self.consume(n);
+        Ok(data)
+    }
+}
+
+fn truncated() -> MyError {
+    io::Error::from(io::ErrorKind::UnexpectedEof).into()
+}
+
+fn le_u16(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([bytes[at], bytes[at + 1]])
+}
+
+fn le_u32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+}
+
+fn le_u64(bytes: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap())
+}
+
+// one local header and its data, None once the central directory (or anything else) starts
+async fn read_stream_entry<R: tokio::io::AsyncRead + Unpin>(
+    stream: &mut StreamReader<R>,
+    stage_dir: &Path,
+    index: usize,
+) -> Result<Option<StreamEntry>, MyError> {
+    let signature = stream.peek(4).await?;
+    if signature.len() < 4 || le_u32(signature, 0) != LOCAL_HEADER_SIG {
+        return Ok(None);
+    }
+    let header = stream.read_vec(30).await?;
+    let flags = le_u16(&header, 6);
+    let method = le_u16(&header, 8);
+    let (time, date) = (le_u16(&header, 10), le_u16(&header, 12));
+    let name = stream.read_vec(le_u16(&header, 26) as usize).await?;
+    let extra = stream.read_vec(le_u16(&header, 28) as usize).await?;
+    let display_name = String::from_utf8_lossy(&name).into_owned();
+    if flags & 1 != 0 {
+        return Err(CustomError::new(&format!(
+            "{display_name} is encrypted, a streamed archive cannot be decrypted"
+        ))
+        .into());
+    }
+    // the zip64 field holds the sizes the header marks with 0xFFFFFFFF, uncompressed first
+    let zip64 = helper::find_extra_field(&extra, ZIP64_EXTRA_ID).filter(|data| data.len() >= 16);
+    let (mut crc32, mut compressed, mut uncompressed) = match zip64 {
+        Some(data) => (le_u32(&header, 14), le_u64(data, 8), le_u64(data, 0)),
+        None => (
+            le_u32(&header, 14),
+            le_u32(&header, 18) as u64,
+            le_u32(&header, 22) as u64,
+        ),
+    };
+    let has_descriptor = flags & 1 << 3 != 0;
+
+    let mut writer =
+        tokio::io::BufWriter::new(File::create(stage_dir.join(index.to_string())).await?);
+    let mut hasher = crc32fast::Hasher::new();
+    let (read, written, descriptor) = match (method, has_descriptor) {
+        (0, false) => {
+            let size = copy_stored(stream, &mut writer, &mut hasher, compressed).await?;
+            (size, size, None)
+        }
+        (0, true) => {
+            let (size, descriptor) = scan_stored(stream, &mut writer, &mut hasher, zip64.is_some()).await?;
+            (size, size, Some(descriptor))
+        }
+        (8, _) => {
+            let (read, written) = inflate(stream, &mut writer, &mut hasher).await?;
+            let descriptor = match has_descriptor {
+                true => Some(read_descriptor(stream, zip64.is_some()).await?),
+                false => None,
+            };
+            (read, written, descriptor)
+        }
+        _ => {
+            return Err(CustomError::new(&format!(
+                "{display_name} uses compression method {method}, a streamed archive takes stored or deflated entries"
+            ))
+            .into())
+        }
+    };
+    tokio::io::AsyncWriteExt::flush(&mut writer).await?;
+    if let Some(descriptor) = descriptor {
+        crc32 = descriptor.crc32;
+        compressed = descriptor.compressed.unwrap_or(read);
+        uncompressed = descriptor.uncompressed.unwrap_or(written);
+    }
+    if hasher.finalize() != crc32 || read != compressed || written != uncompressed {
+        return Err(CustomError::new(&format!(
+            "{display_name} does not match its crc32 and sizes"
+        ))
+        .into());
+    }
+
+    let mtime = helper::extended_timestamp(&extra).or_else(|| {
+        helper::dos_datetime_to_system_time(
+            (date >> 9) + 1980,
+            (date >> 5 & 0x0F) as u8,
+            (date & 0x1F) as u8,
+            (time >> 11) as u8,
+            (time >> 5 & 0x3F) as u8,
+            (time & 0x1F) as u8 * 2,
+        )
+    });
+    Ok(Some(StreamEntry {
+        utf8: flags & 1 << 11 != 0,
+        name,
+        extra,
+        mtime,
+    }))
+}
+
+// an entry whose size the header has
+async fn copy_stored<R, W>(
+    stream: &mut StreamReader<R>,
+    writer: &mut W,
+    hasher: &mut crc32fast::Hasher,
+    size: u64,
+) -> Result<u64, MyError>
+where
+    R: tokio::io::AsyncRead + Unpin,
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    use tokio::io::AsyncWriteExt;
+
+    let mut left = size;
+    while left > 0 {
+        if stream.available().is_empty() && !stream.fill_more().await? {
+            return Err(truncated());
+        }
+        let n = stream.available().len().min(left as usize);
+        let data = &stream.available()[..n];
+        hasher.update(data);
+        writer.write_all(data).await?;
+        stream.consume(n);
+        left -= n as u64;
+    }
+    Ok(size)
+}
+
+// a stored entry with a data descriptor has no end of its own: its end is the first
+// descriptor signature that comes with the crc32 (and sizes, where known) of everything
+// before it. Returns the size and the descriptor
+async fn scan_stored<R, W>(
+    stream: &mut StreamReader<R>,
+    writer: &mut W,
+    hasher: &mut crc32fast::Hasher,
+    zip64: bool,
+) -> Result<(u64, Descriptor), MyError>
+where
+    R: tokio::io::AsyncRead + Unpin,
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    use tokio::io::AsyncWriteExt;
+
+    // signature, crc32, two 64-bit sizes and the signature after them
+    const LOOKAHEAD: usize = 4 + 4 + 16 + 4;
+    let signature = DATA_DESCRIPTOR_SIG.to_le_bytes();
+    let mut size = 0u64;
+    let mut eof = false;
+    loop {
+        let data = stream.available();
+        let found = (0..data.len().saturating_sub(3))
+            .filter(|&at| data[at..at + 4] == signature && (eof || at + LOOKAHEAD <= data.len()))
+            .find_map(|at| {
+                let descriptor = parse_descriptor(&data[at + 4..], zip64)?;
+                let mut crc = hasher.clone();
+                crc.update(&data[..at]);
+                let len = size + at as u64;
+                let matches = descriptor.crc32 == crc.finalize()
+                    && descriptor.compressed.is_none_or(|n| n == len)
+                    && descriptor.uncompressed.is_none_or(|n| n == len);
+                matches.then_some((at, descriptor))
+            });
+        // a descriptor may still start in the bytes held back
+        let n = match &found {
+            Some((at, _)) => *at,
+            None => data.len().saturating_sub(LOOKAHEAD - 1),
+        };
+        hasher.update(&data[..n]);
+        writer.write_all(&data[..n]).await?;
+        stream.consume(n);
+        size += n as u64;
+        if let Some((_, descriptor)) = found {
+            stream.consume(4 + descriptor.len);
+            return Ok((size, descriptor));
+        }
+        if eof {
+            return Err(truncated());
+        }
+        eof = !stream.fill_more().await?;
+    }
+}
+
+// inflate until the deflate stream ends, returns (bytes read, bytes written)
+async fn inflate<R, W>(
+    stream: &mut StreamReader<R>,
+    writer: &mut W,
+    hasher: &mut crc32fast::Hasher,
+) -> Result<(u64, u64), MyError>
+where
+    R: tokio::io::AsyncRead + Unpin,
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    use flate2::{Decompress, FlushDecompress, Status};
+    use tokio::io::AsyncWriteExt;
+
+    let mut inflater = Decompress::new(false);
+    let mut out = vec![0u8; COPY_BUF_LEN];
+    loop {
+        let (read, written) = (inflater.total_in(), inflater.total_out());
+        let status = inflater
+            .decompress(stream.available(), &mut out, FlushDecompress::None)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let consumed = (inflater.total_in() - read) as usize;
+        let produced = (inflater.total_out() - written) as usize;
+        stream.consume(consumed);
+        hasher.update(&out[..produced]);
+        writer.write_all(&out[..produced]).await?;
+        if status == Status::StreamEnd {
+            return Ok((inflater.total_in(), inflater.total_out()));
+        }
+        if consumed == 0 && produced == 0 && !stream.fill_more().await? {
+            return Err(truncated());
+        }
+    }
+}
+
+/// A data descriptor, sizes of `u32::MAX` are left to the zip64 extra field.
+struct Descriptor {
+    crc32: u32,
+    compressed: Option<u64>,
+    uncompressed: Option<u64>,
+    /// bytes after the signature
+    len: usize,
+}
+
+// `bytes` start past the signature. An entry with a zip64 header gets 64-bit sizes, but
+// async_zip writes 32-bit ones in any case, so they are taken only when a signature follows
+fn parse_descriptor(bytes: &[u8], zip64: bool) -> Option<Descriptor> {
+    if zip64 && bytes.get(20..22) == Some(b"PK") {
+        return Some(Descriptor {
+            crc32: le_u32(bytes, 0),
+            compressed: Some(le_u64(bytes, 4)),
+            uncompressed: Some(le_u64(bytes, 12)),
+            len: 20,
+        });
+    }
+    let size = |at| {
+        Some(le_u32(bytes, at))
+            .filter(|&n| n != u32::MAX)
+            .map(u64::from)
+    };
+    (bytes.len() >= 12).then(|| Descriptor {
+        crc32: le_u32(bytes, 0),
+        compressed: size(4),
+        uncompressed: size(8),
+        len: 12,
+    })
+}
+
+// the descriptor right after a deflate stream, its signature is optional
+async fn read_descriptor<R: tokio::io::AsyncRead + Unpin>(
+    stream: &mut StreamReader<R>,
+    zip64: bool,
+) -> Result<Descriptor, MyError> {
+    let signature = stream.peek(4).await?;
+    if signature.len() == 4 && le_u32(signature, 0) == DATA_DESCRIPTOR_SIG {
+        stream.consume(4);
+    }
+    let descriptor = parse_descriptor(stream.peek(24).await?, zip64).ok_or_else(truncated)?;
+    stream.consume(descriptor.len);
+    Ok(descriptor)
+}
+
+async fn extract_into(
+    path: &Path,
+    out_dir: &Path,
+    passwords: &[String],
+    encoding: &NameEncoding,
+    names: &mut Vec<(String, NameSource)>,
+) -> Result<HashMap<String, u32>, MyError> {
+    Ok(match helper::archive_kind(path) {
+        ArchiveKind::SevenZ => sevenz::un7z_inner(path, out_dir)?,
+        ArchiveKind::Tar => tar::untar_inner(path, out_dir, false, encoding)?,
+        ArchiveKind::TarGz => tar::untar_inner(path, out_dir, true, encoding)?,
+        // async_zip cannot decrypt, encrypted archives take the sync path
+        ArchiveKind::Zip => match find_zip_password(path, passwords)? {
+            Some(password) => {
+                println!("[extract_into] {:?} is encrypted, password found", path);
+                let reader = std::fs::File::open(path)?;
+                unzip_inner(reader, out_dir, Some(password.as_bytes()), encoding, names).await?
+            }
+            None => {
+                let reader = File::open(path).await?;
+                unzip_inner_async(reader, out_dir, encoding, names).await?
+                // unzip_inner(reader, out_dir, None).await?;
+            }
+        },
+    })
+}
+
+// Ok(None) if nothing is encrypted, the first password opening the first encrypted entry
+// otherwise
+fn find_zip_password(path: &Path, passwords: &[String]) -> Result<Option<String>, MyError> {
+    // leave archives the sync reader chokes on to the async one
+    let mut zip = match ZipArchive::new(std::fs::File::open(path)?) {
+        Ok(zip) => zip,
+        Err(_) => return Ok(None),
+    };
+
+    let encrypted = (0..zip.len()).find(|&i| {
+        matches!(
+            zip.by_index(i),
+            Err(ZipError::UnsupportedArchive(msg)) if msg == ZipError::PASSWORD_REQUIRED
+        )
+    });
+    let Some(index) = encrypted else {
+        return Ok(None);
+    };
+
+    for password in passwords {
+        if let Ok(Ok(mut file)) = zip.by_index_decrypt(index, password.as_bytes()) {
+            // ZipCrypto lets 1 in 256 wrong passwords through the header check,
+            // reading to the end checks the crc as well
+            if io::copy(&mut file, &mut io::sink()).is_ok() {
+                return Ok(Some(password.clone()));
+            }
+        }
+    }
+
+    Err(MyError::Password(format!(
+        "none of {} password(s) opens {}",
+        passwords.len(),
+        path.to_string_lossy()
+    )))
+}
+
+pub fn find_archives(dir: &Path, archive_ext: &[String], sniff: bool) -> Vec<PathBuf> {
+    WalkDir::new(dir)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| {
+            e.file_type().is_file() && helper::is_archive_file(e.path(), archive_ext, sniff)
+        })
+        .map(|e| e.path().to_owned())
+        .collect()
+}
+
+// expand archives found inside an extracted book into folders named after them,
+// one pass per level so nothing deeper than max_depth is opened
+pub async fn expand_nested(
+    dir: &Path,
+    max_depth: usize,
+    archive_ext: &[String],
+    sniff: bool,
+    passwords: &[String],
+    encoding: &NameEncoding,
+) -> Result<HashMap<String, u32>, MyError> {
+    let mut ret: HashMap<String, u32> = HashMap::new();
+    // kept as they were, a later depth would only fail on them again
+    let mut failed = HashSet::new();
+
+    for depth in 1..=max_depth {
+        let nested: Vec<PathBuf> = find_archives(dir, archive_ext, sniff)
+            .into_iter()
+            .filter(|path| !failed.contains(path))
+            .collect();
+        if nested.is_empty() {
+            break;
+        }
+
+        for path in nested {
+            let out_dir = nested_out_dir(&path);
+            println!("[expand_nested] depth {depth} {:?} -> {:?}", path, out_dir);
+            // inner names end up in their own folders, only the outer book reports them
+            match extract_into(&path, &out_dir, passwords, encoding, &mut vec![]).await {
+                Ok(map) => {
+                    for (k, v) in map {
+                        *ret.entry(k).or_insert(0) += v;
+                    }
+                    fs::remove_file(&path).await?;
+                }
+                Err(e) => {
+                    // keep it as an opaque file, like before
+                    eprintln!("[expand_nested] extract {:?} failed: {e}", path);
+                    if out_dir.exists() {
+                        fs::remove_dir_all(&out_dir).await?;
+                    }
+                    failed.insert(path);
+                }
+            }
+        }
+    }
+
+    Ok(ret)
+}
+
+fn nested_out_dir(path: &Path) -> PathBuf {
+    let mut base = path.with_extension("");
+    if TAR_GZ_EXT.contains(&helper::archive_ext(path).as_str()) {
+        base = base.with_extension("");
+    }
+    let mut out_dir = base.clone();
+    let mut i = 1;
+    while out_dir.exists() {
+        out_dir = PathBuf::from(format!("{}_{i}", base.to_string_lossy()));
+        i += 1;
+    }
+    out_dir
 }
 
 fn zip_dir_inner<T>(
     it: &mut dyn Iterator<Item = DirEntry>,
     prefix: &str,
     writer: T,
-    method: zip::CompressionMethod,
-) -> Result<(), MyError>
+    zip_options: &ZipOptions,
+) -> Result<T, MyError>
 where
     T: Write + Seek,
 {
     let mut zip = zip::ZipWriter::new(writer);
-    let options = FileOptions::default()
-        .compression_method(method)
+    let mut buffer = vec![0u8; COPY_BUF_LEN];
+    let mut dir_options = FileOptions::default()
+        .compression_method(METHOD_STORED)
         .unix_permissions(0o644);
+    if let Some(mtime) = zip_options.deterministic {
+        dir_options = dir_options.last_modified_time(mtime);
+    }
 
     for entry in it {
         let path = entry.path();
@@ -108,89 +1071,303 @@ where
         // Write file or directory explicitly
         // Some unzip tools unzip files with directory paths correctly, some do not!
         if path.is_file() {
-            zip.start_file(name.as_os_str().to_string_lossy(), options)?;
-            let mut f = std::fs::File::open(path)?;
-
-            let mut buffer = Vec::new();
-            f.read_to_end(&mut buffer)?;
-            zip.write_all(&buffer)?;
-            buffer.clear();
+            let mut f = helper::open_extracted(path)?;
+            // carry over what unzip restored on the temp file
+            let attrs = file_attrs(&f.metadata()?);
+            write_entry(
+                &mut zip,
+                name.as_os_str().to_string_lossy().into_owned(),
+                &mut f,
+                attrs,
+                zip_options,
+                &mut buffer,
+            )?;
         } else if !name.as_os_str().is_empty() {
             // Only if not root! Avoids path spec / warning
             // and map name conversion failed error on unzip
-            zip.start_file(name.as_os_str().to_string_lossy(), options)?;
+            zip.add_directory(name.as_os_str().to_string_lossy(), dir_options)?;
         }
     }
 
-    zip.finish()?;
+    if let Some(fingerprint) = &zip_options.fingerprint {
+        zip.set_comment(format!("{FINGERPRINT_PREFIX}{fingerprint}"));
+    }
+
+    Ok(zip.finish()?)
+}
 
+// only the head is read up front, the rest is streamed. Already compressed pages
+// (jpeg, webp...) are only stored, the rest is packed
+fn write_entry<W, R>(
+    zip: &mut zip::ZipWriter<W>,
+    name: String,
+    reader: &mut R,
+    (mtime, mode): (Option<SystemTime>, Option<u32>),
+    zip_options: &ZipOptions,
+    buffer: &mut [u8],
+) -> Result<(), MyError>
+where
+    W: Write + Seek,
+    R: Read,
+{
+    let mut head = Vec::with_capacity(SNIFF_LEN);
+    (&mut *reader)
+        .take(SNIFF_LEN as u64)
+        .read_to_end(&mut head)?;
+    let mut options = if helper::is_compressed_content(&head) {
+        FileOptions::default().compression_method(METHOD_STORED)
+    } else {
+        FileOptions::default()
+            .compression_method(zip_options.method)
+            // stored takes no level at all
+            .compression_level(zip_options.level.filter(|_| zip_options.method != METHOD_STORED))
+    }
+    .unix_permissions(0o644);
+    if let Some(password) = &zip_options.encrypt {
+        options = options.with_deprecated_encryption(password.as_bytes());
+    }
+
+    let mut timestamp = None;
+    match zip_options.deterministic {
+        // the writer always claims a unix host and the extended timestamp is skipped,
+        // so the clock and permissions are the only things left to pin
+        Some(fixed) => options = options.last_modified_time(fixed),
+        None => {
+            if let Some(modified) = mtime.and_then(helper::zip_datetime) {
+                options = options.last_modified_time(modified);
+            }
+            if let Some(mode) = mode {
+                options = options.unix_permissions(mode);
+            }
+            // zip 0.6 cannot write extra data into an encrypted entry
+            if zip_options.encrypt.is_none() {
+                timestamp = mtime.and_then(helper::extended_timestamp_field);
+            }
+        }
+    }
+    match timestamp {
+        Some(timestamp) => {
+            zip.start_file_with_extra_data(name, options)?;
+            zip.write_all(&EXTENDED_TIMESTAMP_ID.to_le_bytes())?;
+            zip.write_all(&(timestamp.len() as u16).to_le_bytes())?;
+            zip.write_all(&timestamp)?;
+            zip.end_extra_data()?;
+        }
+        None => zip.start_file(name, options)?,
+    }
+    zip.write_all(&head)?;
+    helper::copy_with_buffer(reader, zip, buffer)?;
     Ok(())
 }
 
+// mtime and permission bits of a file on disk
+fn file_attrs(metadata: &std::fs::Metadata) -> (Option<SystemTime>, Option<u32>) {
+    #[cfg(unix)]
+    let mode = {
+        use std::os::unix::fs::PermissionsExt;
+        Some(metadata.permissions().mode() & 0o777)
+    };
+    #[cfg(not(unix))]
+    let mode = None;
+    (metadata.modified().ok(), mode)
+}
+
+// streams every file from disk through the entry writer, only a small head of each file
+// is held to choose its compression
 async fn zip_dir_inner_async(
     it: &mut dyn Iterator<Item = DirEntry>,
     prefix: &str,
-    mut file: File,
-    method: Compression,
+    file: &mut File,
+    zip_options: &ZipOptions,
 ) -> Result<(), MyError> {
     use async_zip::tokio::write::ZipFileWriter;
+    use tokio_util::compat::TokioAsyncReadCompatExt;
 
-    let mut writer = ZipFileWriter::with_tokio(&mut file);
-
-    let mut handles = vec![];
+    let mut writer = ZipFileWriter::with_tokio(file);
+    let fixed_date = fixed_date(zip_options);
 
     for entry in it {
-        let path = entry.path().to_owned();
+        let path = entry.path();
         let name = path
             .strip_prefix(Path::new(prefix))
             .ok()
             .ok_or(CustomError::new(&format!(
                 "strip_prefix path {prefix} failed"
-            )))?
-            .to_owned();
-
-        handles.push(tokio::spawn(async {
-            // Write file or directory explicitly
-            // Some unzip tools unzip files with directory paths correctly, some do not!
-            // if path.is_file() {
-            //     zip.start_file(name.as_os_str().to_string_lossy(), options)?;
-            //     let mut f = std::fs::File::open(path)?;
-
-            //     let mut buffer = Vec::new();
-            //     f.read_to_end(&mut buffer)?;
-            //     zip.write_all(&buffer)?;
-            //     buffer.clear();
-            // } else if !name.as_os_str().is_empty() {
-            //     // Only if not root! Avoids path spec / warning
-            //     // and map name conversion failed error on unzip
-            //     zip.start_file(name.as_os_str().to_string_lossy(), options)?;
-            // }
-        }));
-    }
+            )))?;
+        let name = name.as_os_str().to_string_lossy().into_owned();
 
-    for handle in handles {
-        match handle.await {
-            Ok(_) => {}
-            Err(e) => eprint!("{:?}", e),
+        if path.is_file() {
+            let f = File::from_std(helper::open_extracted(path)?);
+            let metadata = f.metadata().await?;
+            let (mtime, mode) = match fixed_date {
+                Some(mtime) => (Some(mtime), Some(0o644)),
+                None => file_attrs(&metadata),
+            };
+            write_entry_streamed(
+                &mut writer,
+                name,
+                &mut f.compat(),
+                (mtime, mode),
+                zip_options,
+            )
+            .await?;
+        } else if !name.is_empty() {
+            // Only if not root!
+            let mut builder = ZipEntryBuilder::new(format!("{name}/").into(), Compression::Stored);
+            let mtime = match fixed_date {
+                Some(mtime) => Some(mtime),
+                None => fs::metadata(path).await?.modified().ok(),
+            };
+            if let Some(mtime) = mtime {
+                builder = builder.last_modification_date(zip_date_time(mtime));
+            }
+            writer.write_entry_whole(builder, &[]).await?;
         }
     }
 
+    if let Some(fingerprint) = &zip_options.fingerprint {
+        writer.comment(format!("{FINGERPRINT_PREFIX}{fingerprint}"));
+    }
     writer.close().await?;
 
     Ok(())
 }
 
+// one entry from any reader, content that does not shrink any further (jpeg, gif, webp,
+// archives..., see `COMPRESSED_MAGIC`) is stored. Only `SNIFF_LEN` bytes are held, the
+// sizes go in a data descriptor after the data
+async fn write_entry_streamed<W, R>(
+    writer: &mut async_zip::base::write::ZipFileWriter<W>,
+    name: String,
+    reader: &mut R,
+    (mtime, mode): (Option<SystemTime>, Option<u32>),
+    zip_options: &ZipOptions,
+) -> Result<(), MyError>
+where
+    W: futures_util::io::AsyncWrite + Unpin,
+    R: futures_util::io::AsyncRead + Unpin,
+{
+    use futures_util::io::{AsyncReadExt, AsyncWriteExt};
+
+    let mut head = vec![0u8; SNIFF_LEN];
+    let mut head_len = 0;
+    while head_len < head.len() {
+        match reader.read(&mut head[head_len..]).await? {
+            0 => break,
+            n => head_len += n,
+        }
+    }
+    head.truncate(head_len);
+
+    let compression = if helper::is_compressed_content(&head) {
+        Compression::Stored
+    } else {
+        async_compression(zip_options.method)
+    };
+    let mut builder = ZipEntryBuilder::new(name.into(), compression);
+    if let Some(mtime) = mtime {
+        builder = builder.last_modification_date(zip_date_time(mtime));
+    }
+    if let Some(mode) = mode {
+        builder = builder.unix_permissions(mode as u16);
+    }
+    // async_zip takes a precise level for every method, `check_level` kept it in the range
+    // of the configured one. Sniffed content is stored whatever the method
+    let level = match compression {
+        Compression::Deflate | Compression::Bz | Compression::Zstd => zip_options.level,
+        _ => None,
+    };
+    if let Some(level) = level.and_then(|level| u32::try_from(level).ok()) {
+        builder = builder.deflate_option(DeflateOption::Other(level));
+    }
+    // same as the sync writer, deterministic books leave the clock out
+    if zip_options.deterministic.is_none() {
+        if let Some(timestamp) = mtime.and_then(helper::extended_timestamp_field) {
+            let mut extra = EXTENDED_TIMESTAMP_ID.to_le_bytes().to_vec();
+            extra.extend_from_slice(&(timestamp.len() as u16).to_le_bytes());
+            extra.extend_from_slice(&timestamp);
+            builder = with_extra_field(builder, &extra).await?;
+        }
+    }
+
+    let mut entry_writer = writer.write_entry_stream(builder).await?;
+    entry_writer.write_all(&head).await?;
+    futures_util::io::copy(reader, &mut entry_writer).await?;
+    entry_writer.close().await?;
+    Ok(())
+}
+
+// the other way around from `raw_extra_field`: async_zip only builds extra fields it parsed
+// itself, so the raw bytes go through a local header of an empty entry
+async fn with_extra_field(
+    builder: ZipEntryBuilder,
+    extra: &[u8],
+) -> Result<ZipEntryBuilder, MyError> {
+    // signature, then version, flags, method, dos time, crc, sizes and name length all zero
+    let mut header = 0x04034b50u32.to_le_bytes().to_vec();
+    header.extend_from_slice(&[0; 24]);
+    header.extend_from_slice(&(extra.len() as u16).to_le_bytes());
+    header.extend_from_slice(extra);
+    let reader = async_zip::base::read::stream::ZipFileReader::new(header.as_slice());
+    match reader.next_with_entry().await? {
+        Some(parsed) => Ok(builder.extra_fields(parsed.reader().entry().extra_fields().to_vec())),
+        None => Err(MyError::from(CustomError::new(
+            "extra field header not parsed",
+        ))),
+    }
+}
+
+// the deterministic mtime as a point in time
+fn fixed_date(zip_options: &ZipOptions) -> Option<SystemTime> {
+    zip_options.deterministic.and_then(|mtime| {
+        helper::dos_datetime_to_system_time(
+            mtime.year(),
+            mtime.month(),
+            mtime.day(),
+            mtime.hour(),
+            mtime.minute(),
+            mtime.second(),
+        )
+    })
+}
+
+fn async_compression(method: zip::CompressionMethod) -> Compression {
+    match method {
+        zip::CompressionMethod::Stored => Compression::Stored,
+        zip::CompressionMethod::Bzip2 => Compression::Bz,
+        zip::CompressionMethod::Zstd => Compression::Zstd,
+        _ => Compression::Deflate,
+    }
+}
+
+// local time as well, see `helper::zip_datetime`. async_zip only takes it labelled as UTC
+fn zip_date_time(time: SystemTime) -> ZipDateTime {
+    use chrono::TimeZone;
+
+    let local = chrono::DateTime::<chrono::Local>::from(time).naive_local();
+    ZipDateTime::from_chrono(&chrono::Utc.from_utc_datetime(&local))
+}
+
 async fn unzip_inner(
     reader: std::fs::File,
     out_dir: &Path,
+    password: Option<&[u8]>,
+    encoding: &NameEncoding,
+    names: &mut Vec<(String, NameSource)>,
 ) -> Result<HashMap<String, u32>, MyError> {
     let mut ret: HashMap<String, u32> = HashMap::new();
     let mut zip = ZipArchive::new(reader)?;
     let zip_len = zip.len();
+    let encoding = zip_archive_encoding(&mut zip, encoding)?;
 
     for i in 0..zip_len {
         let time_other = std::time::Instant::now();
-        let mut file = zip.by_index(i)?;
+        let mut file = match password {
+            Some(password) => zip
+                .by_index_decrypt(i, password)?
+                .map_err(|_| MyError::Password(format!("entry {i} has a different password")))?,
+            None => zip.by_index(i)?,
+        };
         let entry_name = file.name_raw();
 
         // let extra_data = encoding::label::encoding_from_whatwg_label("UTF-8")
@@ -199,9 +1376,17 @@ async fn unzip_inner(
         //     .unwrap();
         // println!("extra data: {extra_data}");
 
-        let decoded_entry_name = helper::decode_zip_filename(entry_name)?;
+        let (decoded_entry_name, source) = helper::decode_zip_filename(
+            entry_name,
+            utf8_flag(&file),
+            file.extra_data(),
+            &encoding,
+        )?;
 
         helper::validate_file_name(decoded_entry_name.as_str())?;
+        names.push((decoded_entry_name.clone(), source));
+
+        let (mtime, mode) = zip_file_attrs(&file);
 
         let out_path = out_dir.join(&decoded_entry_name);
         // println!("[unzip_inner] unzip out_path: {:?}", out_path);
@@ -231,6 +1416,8 @@ async fn unzip_inner(
             let time_other = time_other.elapsed().as_nanos() as f64 / 1000.0;
             let time = std::time::Instant::now();
             io::copy(&mut file, &mut out_file)?;
+            drop(out_file);
+            helper::restore_file_attrs(&out_path, mtime, mode)?;
             println!(
                 "[unzip_inner] copy {:?} -> {:?} ({:.2} ms unzip & I/O, {:.2} μs other)",
                 decoded_entry_name,
@@ -248,7 +1435,113 @@ async fn unzip_inner(
     Ok(ret)
 }
 
-async fn unzip_inner_async(archive: File, out_dir: &Path) -> Result<HashMap<String, u32>, MyError> {
+// the extended timestamp beats the 2-second, timezone-less dos time
+fn zip_file_attrs(file: &zip::read::ZipFile) -> (Option<SystemTime>, Option<u32>) {
+    let modified = file.last_modified();
+    let mtime = helper::extended_timestamp(file.extra_data()).or_else(|| {
+        helper::dos_datetime_to_system_time(
+            modified.year(),
+            modified.month(),
+            modified.day(),
+            modified.hour(),
+            modified.minute(),
+            modified.second(),
+        )
+    });
+    (mtime, file.unix_mode())
+}
+
+// see `helper::archive_encoding`, nothing to decide with a forced encoding. The raw
+// reader opens encrypted entries as well
+fn zip_archive_encoding<R: Read + Seek>(
+    zip: &mut ZipArchive<R>,
+    encoding: &NameEncoding,
+) -> Result<NameEncoding, MyError> {
+    let mut encoding = encoding.clone();
+    if encoding.forced.is_some() {
+        return Ok(encoding);
+    }
+    let mut names = vec![];
+    for i in 0..zip.len() {
+        let file = zip.by_index_raw(i)?;
+        names.push((file.name_raw().to_vec(), file.extra_data().to_vec()));
+    }
+    encoding.archive = helper::archive_encoding(
+        names
+            .iter()
+            .map(|(raw, extra)| (raw.as_slice(), extra.as_slice())),
+        &encoding.candidates,
+    )
+    .chosen;
+    Ok(encoding)
+}
+
+/// How the entry names of a zip are decoded, from the central directory alone.
+pub fn inspect(path: &Path, encoding: &NameEncoding) -> Result<ArchiveDiagnostic, MyError> {
+    let mut zip = ZipArchive::new(std::fs::File::open(path)?)?;
+    let mut names = vec![];
+    for i in 0..zip.len() {
+        let file = zip.by_index_raw(i)?;
+        names.push((
+            file.name_raw().to_vec(),
+            utf8_flag(&file),
+            file.extra_data().to_vec(),
+        ));
+    }
+    crate::inspect::diagnose(&path.to_string_lossy(), &names, encoding)
+}
+
+// the zip crate keeps bit 11 to itself, but decodes flagged names as utf-8 and the others
+// as cp437, which turns every non-ascii byte into a multi-byte char
+fn utf8_flag(file: &zip::read::ZipFile) -> bool {
+    file.name().as_bytes() == file.name_raw()
+}
+
+// same for async_zip entries, `extra` from `raw_extra_field`
+fn entry_attrs(entry: &ZipEntry, extra: &[u8]) -> (Option<SystemTime>, Option<u32>) {
+    let modified = entry.last_modification_date();
+    let mtime = helper::extended_timestamp(extra).or_else(|| {
+        helper::dos_datetime_to_system_time(
+            modified.year() as u16,
+            modified.month() as u8,
+            modified.day() as u8,
+            modified.hour() as u8,
+            modified.minute() as u8,
+            modified.second() as u8,
+        )
+    });
+    (mtime, entry.unix_permissions().map(u32::from))
+}
+
+// bit 11 of the general purpose flags, which sit 6 bytes into the local header
+async fn local_utf8_flag(archive: &mut File, header_offset: u64) -> Result<bool, MyError> {
+    use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+    let mut flags = [0u8; 2];
+    archive.seek(io::SeekFrom::Start(header_offset + 6)).await?;
+    archive.read_exact(&mut flags).await?;
+    Ok(u16::from_le_bytes(flags) & 1 << 11 != 0)
+}
+
+// async_zip only hands out its parsed extra fields, with a type it keeps private. The raw
+// bytes come back out of a local header it writes for them
+async fn raw_extra_field(entry: &ZipEntry) -> Result<Vec<u8>, MyError> {
+    let mut writer = async_zip::base::write::ZipFileWriter::new(vec![]);
+    let builder = ZipEntryBuilder::new(String::new().into(), Compression::Stored)
+        .extra_fields(entry.extra_fields().to_vec());
+    writer.write_entry_whole(builder, &[]).await?;
+    let header = writer.close().await?;
+    // the extra field length sits at 28, the extra field after the empty name at 30
+    let len = u16::from_le_bytes([header[28], header[29]]) as usize;
+    Ok(header[30..30 + len].to_vec())
+}
+
+async fn unzip_inner_async(
+    archive: File,
+    out_dir: &Path,
+    encoding: &NameEncoding,
+    names: &mut Vec<(String, NameSource)>,
+) -> Result<HashMap<String, u32>, MyError> {
     use async_zip::tokio::read::seek::ZipFileReader;
     use tokio::fs::OpenOptions;
     use tokio_util::compat::TokioAsyncWriteCompatExt;
@@ -256,18 +1549,45 @@ async fn unzip_inner_async(archive: File, out_dir: &Path) -> Result<HashMap<Stri
     let ret: HashMap<String, u32> = HashMap::new();
     // let archive = archive.compat();
     let debug_filename = format!("{:?}", archive);
+    let mut headers = archive.try_clone().await?;
     let zip = ZipFileReader::with_tokio(archive).await?;
+    // async_zip calls every name that happens to be valid utf-8 Utf8, the flag itself is only
+    // in the local headers
+    let mut utf8_flags = vec![];
+    for entry in zip.file().entries() {
+        utf8_flags.push(local_utf8_flag(&mut headers, entry.header_offset()).await?);
+    }
+    let mut encoding = encoding.clone();
+    if encoding.forced.is_none() {
+        let mut extras = vec![];
+        for entry in zip.file().entries() {
+            extras.push(raw_extra_field(entry.entry()).await?);
+        }
+        encoding.archive = helper::archive_encoding(
+            zip.file()
+                .entries()
+                .iter()
+                .zip(&extras)
+                .map(|(entry, extra)| (entry.entry().filename().as_bytes(), extra.as_slice())),
+            &encoding.candidates,
+        )
+        .chosen;
+    }
 
     let zip_arc = Arc::new(Mutex::new(zip));
     let ret_arc = Arc::new(Mutex::new(ret));
+    let names_arc = Arc::new(Mutex::new(vec![]));
 
     let mut handles = vec![];
 
-    for index in 0..zip_arc.lock().await.file().entries().len() {
+    // one flag per entry of the central directory
+    for (index, utf8_flag) in utf8_flags.into_iter().enumerate() {
         let out_dir = out_dir.to_owned();
 
         let zip_arc = Arc::clone(&zip_arc); // 克隆
         let ret_arc = Arc::clone(&ret_arc); // 克隆
+        let names_arc = Arc::clone(&names_arc);
+        let encoding = encoding.clone();
 
         let debug_header = "[unzip_inner_async] [tokio thread]";
         let debug_archive = debug_filename.clone();
@@ -292,10 +1612,21 @@ async fn unzip_inner_async(archive: File, out_dir: &Path) -> Result<HashMap<Stri
                         Chalk::new().light_blue().string(&debug_header),
                         out_dir
                     );
-                    let filename = entry.entry().filename().as_bytes();
-                    if let Ok(decoded_entry_name) = helper::decode_zip_filename(filename) {
+                    let filename = entry.entry().filename();
+                    let extra = raw_extra_field(entry.entry()).await.unwrap_or_default();
+                    let (mtime, mode) = entry_attrs(entry.entry(), &extra);
+                    if let Ok((decoded_entry_name, source)) = helper::decode_zip_filename(
+                        filename.as_bytes(),
+                        utf8_flag,
+                        &extra,
+                        &encoding,
+                    ) {
                         match helper::validate_file_name(&decoded_entry_name) {
                             Ok(_) => {
+                                names_arc
+                                    .lock()
+                                    .await
+                                    .push((decoded_entry_name.clone(), source));
                                 let path = out_dir.join(&decoded_entry_name);
 
                                 match zip_arc_guard.reader_with_entry(index).await {
@@ -350,7 +1681,13 @@ async fn unzip_inner_async(archive: File, out_dir: &Path) -> Result<HashMap<Stri
                                                     .await
                                                     {
                                                         Err(e) => eprint!("{:?}", e),
-                                                        _ => {}
+                                                        _ => {
+                                                            if let Err(e) = helper::restore_file_attrs(
+                                                                &path, mtime, mode,
+                                                            ) {
+                                                                eprint!("{:?}", e)
+                                                            }
+                                                        }
                                                     }
                                                 }
                                                 Err(e) => eprint!("{:?}", e),
@@ -387,5 +1724,282 @@ async fn unzip_inner_async(archive: File, out_dir: &Path) -> Result<HashMap<Stri
         let ret_lock = ret_arc.lock().await;
         ret_lock.clone()
     };
+    // tasks finish in any order
+    let mut decoded = names_arc.lock().await.clone();
+    decoded.sort_by(|a, b| a.0.cmp(&b.0));
+    names.append(&mut decoded);
     Ok(result)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha2::{Digest, Sha256};
+
+    fn options(writer: ZipWriterKind) -> ZipOptions {
+        ZipOptions {
+            method: zip::CompressionMethod::Deflated,
+            level: None,
+            deterministic: Some(zip::DateTime::default()),
+            fingerprint: None,
+            writer,
+            encrypt: None,
+        }
+    }
+
+    async fn zip_hash(src: &Path, dst: &Path, writer: ZipWriterKind) -> Vec<u8> {
+        zip_dir(
+            src.to_str().unwrap(),
+            dst.to_str().unwrap(),
+            None,
+            options(writer),
+        )
+        .await
+        .unwrap();
+        Sha256::digest(std::fs::read(dst).unwrap()).to_vec()
+    }
+
+    #[tokio::test]
+    async fn deterministic_output_is_byte_identical() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("book");
+        std::fs::create_dir_all(src.join("c01")).unwrap();
+        std::fs::write(src.join("c01/001.jpg"), b"\xFF\xD8\xFFpage one").unwrap();
+        std::fs::write(src.join("c01/002.jpg"), b"\xFF\xD8\xFFpage two").unwrap();
+        std::fs::write(src.join("info.xml"), b"<info>text text text</info>").unwrap();
+
+        for writer in [ZipWriterKind::Sync, ZipWriterKind::Async] {
+            let first = zip_hash(&src, &dir.path().join("a.cbz"), writer).await;
+            // another mtime must not show in the output
+            filetime::set_file_mtime(
+                src.join("info.xml"),
+                filetime::FileTime::from_unix_time(1_000_000_000, 0),
+            )
+            .unwrap();
+            let second = zip_hash(&src, &dir.path().join("b.cbz"), writer).await;
+            assert_eq!(first, second, "{writer:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn pipeline_raw_copy_keeps_method_crc_and_timestamp() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src.zip");
+        let text = b"text text text text text text text text".repeat(8);
+        let mtime = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_600_000_001);
+        let mut writer = zip::ZipWriter::new(std::fs::File::create(&src).unwrap());
+        let deflated = FileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+        // a plain raw copy first, so the stamped one is not the first central record
+        writer.start_file("a.txt", deflated).unwrap();
+        writer.write_all(b"plain").unwrap();
+        writer
+            .start_file_with_extra_data("b.txt", deflated)
+            .unwrap();
+        writer
+            .write_all(&EXTENDED_TIMESTAMP_ID.to_le_bytes())
+            .unwrap();
+        writer.write_all(&5u16.to_le_bytes()).unwrap();
+        writer
+            .write_all(&helper::extended_timestamp_field(mtime).unwrap())
+            .unwrap();
+        writer.end_extra_data().unwrap();
+        writer.write_all(&text).unwrap();
+        writer.finish().unwrap();
+
+        let pipeline = Pipeline {
+            needs_extraction: &|_, _| false,
+            keep: &|_| true,
+            transform_name: &|_| None,
+            transform: &|_, _| Ok(()),
+        };
+        let dst = dir.path().join("dst.zip");
+        transcode(
+            src.to_str().unwrap(),
+            dst.to_str().unwrap(),
+            &pipeline,
+            &ZipOptions {
+                deterministic: None,
+                ..options(ZipWriterKind::Sync)
+            },
+            &NameEncoding::default(),
+        )
+        .await
+        .unwrap();
+
+        let mut zip = ZipArchive::new(std::fs::File::open(&dst).unwrap()).unwrap();
+        let mut file = zip.by_name("b.txt").unwrap();
+        assert_eq!(file.compression(), zip::CompressionMethod::Deflated);
+        assert_eq!(file.crc32(), crc32fast::hash(&text));
+        assert_eq!(file.size(), text.len() as u64);
+        assert_eq!(helper::extended_timestamp(file.extra_data()), Some(mtime));
+        let mut content = vec![];
+        file.read_to_end(&mut content).unwrap();
+        assert_eq!(content, text);
+        drop(file);
+        let mut plain = String::new();
+        zip.by_name("a.txt")
+            .unwrap()
+            .read_to_string(&mut plain)
+            .unwrap();
+        assert_eq!(plain, "plain");
+    }
+
+    #[tokio::test]
+    async fn async_reader_reads_the_utf8_flag_from_the_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let book = dir.path().join("book.zip");
+        let mut writer = zip::ZipWriter::new(std::fs::File::create(&book).unwrap());
+        writer
+            .start_file("\u{9875}.txt", FileOptions::default())
+            .unwrap();
+        writer.write_all(b"page").unwrap();
+        writer.finish().unwrap();
+        // the same valid utf-8 name, but without bit 11 in the local and central header
+        let mut bytes = std::fs::read(&book).unwrap();
+        let central = bytes
+            .windows(4)
+            .position(|w| w == 0x02014b50u32.to_le_bytes())
+            .unwrap();
+        bytes[7] &= !0x08;
+        bytes[central + 9] &= !0x08;
+        std::fs::write(&book, bytes).unwrap();
+
+        let encoding = NameEncoding {
+            forced: Some("gbk".to_string()),
+            ..NameEncoding::default()
+        };
+        let (_, names, out) = unzip(book.to_string_lossy().into_owned(), &[], &encoding)
+            .await
+            .unwrap();
+        assert_eq!(names.len(), 1);
+        assert_eq!(names[0].1, NameSource::Forced("gbk".to_string()));
+        std::fs::remove_dir_all(out).unwrap();
+    }
+
+    // stored pages, one of them longer than a read, and a deflated text
+    async fn stream_book(dir: &Path, writer: ZipWriterKind) -> PathBuf {
+        let src = dir.join("book");
+        std::fs::create_dir_all(src.join("c01")).unwrap();
+        std::fs::write(src.join("c01/001.jpg"), b"\xFF\xD8\xFFpage one").unwrap();
+        std::fs::write(src.join("c01/002.jpg"), long_page()).unwrap();
+        std::fs::write(src.join("info.xml"), b"<info>text text text</info>").unwrap();
+        let book = dir.join("book.cbz");
+        zip_dir(
+            src.to_str().unwrap(),
+            book.to_str().unwrap(),
+            None,
+            ZipOptions {
+                deterministic: None,
+                ..options(writer)
+            },
+        )
+        .await
+        .unwrap();
+        book
+    }
+
+    fn long_page() -> Vec<u8> {
+        let mut page = b"\xFF\xD8\xFF".to_vec();
+        page.extend((0..3 * COPY_BUF_LEN as u32).map(|i| (i.wrapping_mul(2654435761) >> 24) as u8));
+        page
+    }
+
+    #[tokio::test]
+    async fn stream_reads_back_the_async_writer() {
+        let dir = tempfile::tempdir().unwrap();
+        // both entries come with a data descriptor
+        let book = stream_book(dir.path(), ZipWriterKind::Async).await;
+
+        let piped = std::fs::read(&book).unwrap();
+        let (_, names, out) = unzip_stream(piped.as_slice(), &NameEncoding::default())
+            .await
+            .unwrap();
+        let out = Path::new(&out);
+        assert_eq!(
+            std::fs::read(out.join("c01/001.jpg")).unwrap(),
+            b"\xFF\xD8\xFFpage one"
+        );
+        assert_eq!(std::fs::read(out.join("c01/002.jpg")).unwrap(), long_page());
+        assert_eq!(
+            std::fs::read(out.join("info.xml")).unwrap(),
+            b"<info>text text text</info>"
+        );
+        assert!(names.iter().any(|(name, _)| name == "c01/001.jpg"));
+        std::fs::remove_dir_all(out).unwrap();
+    }
+
+    #[tokio::test]
+    async fn stream_reads_back_the_sync_writer() {
+        let dir = tempfile::tempdir().unwrap();
+        // sizes in the local headers, no descriptors
+        let book = stream_book(dir.path(), ZipWriterKind::Sync).await;
+
+        let piped = std::fs::read(&book).unwrap();
+        let (ret, _, out) = unzip_stream(piped.as_slice(), &NameEncoding::default())
+            .await
+            .unwrap();
+        let out = Path::new(&out);
+        assert_eq!(
+            std::fs::read(out.join("info.xml")).unwrap(),
+            b"<info>text text text</info>"
+        );
+        assert_eq!(ret.get("jpg"), Some(&2));
+        assert_eq!(std::fs::read(out.join("c01/002.jpg")).unwrap(), long_page());
+        std::fs::remove_dir_all(out).unwrap();
+    }
+
+    #[tokio::test]
+    async fn stream_rejects_a_wrong_crc() {
+        let dir = tempfile::tempdir().unwrap();
+        let book = stream_book(dir.path(), ZipWriterKind::Async).await;
+
+        let mut piped = std::fs::read(&book).unwrap();
+        // the descriptor of the deflated text, the last entry
+        let at = piped
+            .windows(4)
+            .rposition(|w| w == DATA_DESCRIPTOR_SIG.to_le_bytes())
+            .unwrap();
+        piped[at + 4] ^= 0xFF;
+        assert!(
+            unzip_stream(piped.as_slice(), &NameEncoding::default())
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn expand_nested_keeps_a_failing_inner_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let inner = dir.path().join("vol1.zip");
+        let mut writer = zip::ZipWriter::new(std::fs::File::create(&inner).unwrap());
+        writer
+            .start_file("001.jpg", FileOptions::default())
+            .unwrap();
+        writer.write_all(b"page").unwrap();
+        writer.finish().unwrap();
+        // a zip signature and nothing after it
+        std::fs::write(dir.path().join("vol2.zip"), b"PK\x03\x04broken").unwrap();
+
+        let ret = expand_nested(
+            dir.path(),
+            3,
+            &["zip".to_string()],
+            true,
+            &[],
+            &NameEncoding::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(ret.get("jpg"), Some(&1));
+        assert_eq!(
+            std::fs::read(dir.path().join("vol1/001.jpg")).unwrap(),
+            b"page"
+        );
+        assert!(!inner.exists());
+        assert_eq!(
+            std::fs::read(dir.path().join("vol2.zip")).unwrap(),
+            b"PK\x03\x04broken"
+        );
+        assert!(!dir.path().join("vol2").exists());
+    }
+}